@@ -1,9 +1,15 @@
 extern crate tcfetch as tcfetch_rs;
-use pyo3::exceptions::PyOSError;
+use pyo3::exceptions::{PyDeprecationWarning, PyOSError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 use std::env;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tcfetch_rs::progress::ProgressObserver;
+use tcfetch_rs::taskcluster::{Artifact, Credentials, TaskGroupTask};
+use tcfetch_rs::DownloadStatus;
 
 #[derive(Debug)]
 struct Error(tcfetch_rs::Error);
@@ -26,6 +32,50 @@ impl std::convert::From<Error> for PyErr {
     }
 }
 
+/// The configuration a Gecko test task runs in.
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct TestSetting {
+    #[pyo3(get)]
+    pub os: String,
+    #[pyo3(get)]
+    pub os_version: Option<String>,
+    #[pyo3(get)]
+    pub arch: String,
+    #[pyo3(get)]
+    pub device: Option<String>,
+    #[pyo3(get)]
+    pub build_type: String,
+    #[pyo3(get)]
+    pub build_flags: Vec<String>,
+    #[pyo3(get)]
+    pub runtime: Vec<String>,
+    #[pyo3(get)]
+    pub display_name: String,
+    #[pyo3(get)]
+    pub hash: Option<String>,
+}
+
+impl TestSetting {
+    fn from_test_setting(test_setting: tcfetch_rs::taskcluster::TestSetting) -> Self {
+        TestSetting {
+            display_name: test_setting.display_name(),
+            build_flags: to_strings(test_setting.build_flags()),
+            runtime: to_strings(test_setting.runtime_flags()),
+            os: test_setting.platform.os.name,
+            os_version: test_setting.platform.os.version,
+            arch: test_setting.platform.arch,
+            device: test_setting.platform.device,
+            build_type: test_setting.build.build_type,
+            hash: test_setting.hash,
+        }
+    }
+}
+
+fn to_strings(values: Vec<&str>) -> Vec<String> {
+    values.into_iter().map(|value| value.to_owned()).collect()
+}
+
 #[pyclass(frozen)]
 pub struct TaskDownloadData {
     #[pyo3(get)]
@@ -33,55 +83,424 @@ pub struct TaskDownloadData {
     #[pyo3(get)]
     pub name: String,
     #[pyo3(get)]
-    pub path: PathBuf,
+    pub artifact: Option<String>,
+    #[pyo3(get)]
+    pub path: Option<PathBuf>,
+    #[pyo3(get)]
+    pub test_setting_hash: Option<String>,
+    #[pyo3(get)]
+    pub task_run_id: Option<u64>,
+    #[pyo3(get)]
+    pub test_setting: Option<TestSetting>,
+    #[pyo3(get)]
+    pub status: &'static str,
+    #[pyo3(get)]
+    pub error: Option<String>,
     #[pyo3(get)]
-    pub run_id: Option<String>,
+    pub sha256: Option<String>,
 }
 
 impl TaskDownloadData {
-    fn from_download(task: tcfetch_rs::taskcluster::TaskGroupTask, download_path: PathBuf) -> Self {
+    fn from_download(download: tcfetch_rs::TaskDownload) -> Self {
+        let task = download.task;
+        let test_setting = task.test_setting();
+        let status = download.status.as_str();
+        let error = download.status.error().map(|err| err.to_string());
+        let path = match download.status {
+            DownloadStatus::Downloaded(path)
+            | DownloadStatus::SkippedExisting(path)
+            | DownloadStatus::Cached(path)
+            | DownloadStatus::DownloadFailed(path, _) => Some(path),
+            DownloadStatus::ArtifactMissing
+            | DownloadStatus::ListingFailed(_)
+            | DownloadStatus::TimedOut(_) => None,
+        };
         TaskDownloadData {
             id: task.status.taskId,
             name: task.task.metadata.name,
-            path: download_path,
-            run_id: task
-                .task
-                .extra
-                .get("test-setting")
-                .and_then(|x| x.get("_hash"))
-                .and_then(|x| x.as_str())
-                .map(|x| x.to_owned()),
+            artifact: download.artifact.map(|artifact| artifact.name),
+            path,
+            test_setting_hash: test_setting
+                .as_ref()
+                .and_then(|test_setting| test_setting.hash.clone()),
+            task_run_id: download.run_id,
+            test_setting: test_setting.map(TestSetting::from_test_setting),
+            status,
+            error,
+            sha256: download.sha256,
+        }
+    }
+}
+
+#[pymethods]
+impl TaskDownloadData {
+    /// Deprecated alias for `test_setting_hash`.
+    #[getter]
+    fn run_id(&self, py: Python<'_>) -> PyResult<Option<String>> {
+        PyErr::warn(
+            py,
+            &py.get_type::<PyDeprecationWarning>(),
+            c"TaskDownloadData.run_id is deprecated, use test_setting_hash instead",
+            1,
+        )?;
+        Ok(self.test_setting_hash.clone())
+    }
+}
+
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct RetryPolicy(tcfetch_rs::RetryPolicy);
+
+#[pymethods]
+impl RetryPolicy {
+    #[new]
+    #[pyo3(signature = (max_attempts=None, initial_backoff=None, max_backoff=None, jitter=None, retry_statuses=None, honor_retry_after=None))]
+    fn new(
+        max_attempts: Option<u32>,
+        initial_backoff: Option<f64>,
+        max_backoff: Option<f64>,
+        jitter: Option<bool>,
+        retry_statuses: Option<Vec<u16>>,
+        honor_retry_after: Option<bool>,
+    ) -> PyResult<Self> {
+        let mut retry = tcfetch_rs::RetryPolicy::default();
+        if let Some(max_attempts) = max_attempts {
+            retry.max_attempts = max_attempts.max(1);
+        }
+        if let Some(backoff) = initial_backoff {
+            retry.initial_backoff = duration(backoff)?;
+        }
+        if let Some(backoff) = max_backoff {
+            retry.max_backoff = duration(backoff)?;
+        }
+        if let Some(jitter) = jitter {
+            retry.jitter = jitter;
+        }
+        if let Some(retry_statuses) = retry_statuses {
+            retry.retry_statuses = retry_statuses;
+        }
+        if let Some(honor_retry_after) = honor_retry_after {
+            retry.honor_retry_after = honor_retry_after;
+        }
+        Ok(RetryPolicy(retry))
+    }
+
+    #[getter]
+    fn max_attempts(&self) -> u32 {
+        self.0.max_attempts
+    }
+
+    #[getter]
+    fn initial_backoff(&self) -> f64 {
+        self.0.initial_backoff.as_secs_f64()
+    }
+
+    #[getter]
+    fn max_backoff(&self) -> f64 {
+        self.0.max_backoff.as_secs_f64()
+    }
+
+    #[getter]
+    fn jitter(&self) -> bool {
+        self.0.jitter
+    }
+
+    #[getter]
+    fn retry_statuses(&self) -> Vec<u16> {
+        self.0.retry_statuses.clone()
+    }
+
+    #[getter]
+    fn honor_retry_after(&self) -> bool {
+        self.0.honor_retry_after
+    }
+}
+
+fn duration(secs: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| PyValueError::new_err(format!("Invalid duration {}", secs)))
+}
+
+/// Create a client, using Taskcluster credentials from `credentials_file` or
+/// the environment, and a GitHub token from `github_token` or `GITHUB_TOKEN`.
+fn client(
+    retry: Option<RetryPolicy>,
+    credentials_file: Option<&str>,
+    github_token: Option<String>,
+    wait_for_rate_limit: bool,
+    response_cache: Option<tcfetch_rs::cache::ResponseCache>,
+) -> Result<tcfetch_rs::Client, Error> {
+    let mut client = tcfetch_rs::Client::new(retry.map(|retry| retry.0).unwrap_or_default())
+        .with_wait_for_github_rate_limit(wait_for_rate_limit);
+    if let Some(cache) = response_cache {
+        client = client.with_response_cache(cache);
+    }
+    if let Some(token) = github_token
+        .or_else(|| env::var("GITHUB_TOKEN").ok())
+        .filter(|token| !token.is_empty())
+    {
+        client = client.with_github_token(token);
+    }
+    let credentials = match credentials_file {
+        Some(path) => Some(Credentials::from_file(Path::new(path))?),
+        None => Credentials::from_env(),
+    };
+    Ok(match credentials {
+        Some(credentials) => client.with_taskcluster_credentials(credentials),
+        None => client,
+    })
+}
+
+/// Resolve a repo spec using the built-in repos and those in `config_file` (or
+/// the default config file), overriding the decision check name for GitHub repos.
+fn project(
+    branch: &str,
+    config_file: Option<&str>,
+    check_name: Option<String>,
+) -> Result<tcfetch_rs::Project, Error> {
+    let config = tcfetch_rs::Config::load(config_file.map(Path::new))?;
+    let mut project = config.project(branch)?;
+    if let Some(check_name) = check_name {
+        match project {
+            tcfetch_rs::Project::Github(ref mut project) => project.check_name = check_name,
+            tcfetch_rs::Project::Hgmo(_) => {
+                return Err(tcfetch_rs::Error::String(
+                    "check_name is only supported for GitHub repos".into(),
+                )
+                .into())
+            }
         }
     }
+    Ok(project)
+}
+
+/// The cache settings from the config file, with `cache_dir` and
+/// `cache_max_size` overriding the configured values, or `None` if `cache`
+/// isn't set.
+fn cache_config(
+    config_file: Option<&str>,
+    cache: bool,
+    cache_dir: Option<&str>,
+    cache_max_size: Option<String>,
+) -> Result<Option<tcfetch_rs::config::CacheConfig>, Error> {
+    if !cache {
+        return Ok(None);
+    }
+    let mut cache_config = tcfetch_rs::Config::load(config_file.map(Path::new))?.cache;
+    cache_config.merge(tcfetch_rs::config::CacheConfig {
+        enabled: Some(true),
+        dir: cache_dir.map(PathBuf::from),
+        max_size: cache_max_size,
+        link: None,
+    });
+    Ok(Some(cache_config))
+}
+
+/// The source of tasks given by exactly one of `commit`, `task_group`, `task`
+/// and `index`.
+fn task_source(
+    commit: Option<String>,
+    task_group: Option<String>,
+    task: Option<String>,
+    index: Option<String>,
+) -> Result<tcfetch_rs::TaskSource, Error> {
+    let mut sources = [
+        commit.map(tcfetch_rs::TaskSource::Commit),
+        task_group.map(tcfetch_rs::TaskSource::TaskGroup),
+        task.map(tcfetch_rs::TaskSource::Task),
+        index.map(tcfetch_rs::TaskSource::Index),
+    ]
+    .into_iter()
+    .flatten();
+    match (sources.next(), sources.next()) {
+        (Some(source), None) => Ok(source),
+        _ => Err(tcfetch_rs::Error::String(
+            "Exactly one of commit, task_group, task and index must be given".into(),
+        )
+        .into()),
+    }
 }
 
 #[pyfunction]
-#[pyo3(signature = (branch, commit, taskcluster_base=None))]
+#[pyo3(signature = (branch, commit=None, taskcluster_base=None, retry=None, credentials_file=None, github_token=None, wait_for_rate_limit=false, check_name=None, config_file=None, task_group=None, task=None, index=None))]
+#[allow(clippy::too_many_arguments)]
 pub fn check_complete(
     branch: &str,
-    commit: &str,
+    commit: Option<String>,
     taskcluster_base: Option<&str>,
+    retry: Option<RetryPolicy>,
+    credentials_file: Option<&str>,
+    github_token: Option<String>,
+    wait_for_rate_limit: bool,
+    check_name: Option<String>,
+    config_file: Option<&str>,
+    task_group: Option<String>,
+    task: Option<String>,
+    index: Option<String>,
 ) -> PyResult<bool> {
-    Ok(tcfetch_rs::check_complete(taskcluster_base, branch, commit).map_err(Error::from)?)
+    let client = client(
+        retry,
+        credentials_file,
+        github_token,
+        wait_for_rate_limit,
+        None,
+    )?;
+    Ok(tcfetch_rs::check_complete(
+        &client,
+        taskcluster_base,
+        &project(branch, config_file, check_name)?,
+        &task_source(commit, task_group, task, index)?,
+    )
+    .map_err(Error::from)?)
+}
+
+/// Forwards progress notifications to a Python callable, which is called as
+/// `progress(event, data)` with a dict of data about the event. The first
+/// exception raised by the callable is re-raised once the fetch finishes, and
+/// no further notifications are sent.
+struct PyProgress {
+    callback: PyObject,
+    error: Mutex<Option<PyErr>>,
+}
+
+impl PyProgress {
+    fn notify(&self, event: &str, data: impl FnOnce(&Bound<'_, PyDict>) -> PyResult<()>) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_some() {
+            return;
+        }
+        if let Err(err) = Python::with_gil(|py| {
+            let dict = PyDict::new(py);
+            data(&dict)?;
+            self.callback.call1(py, (event, dict))?;
+            Ok::<_, PyErr>(())
+        }) {
+            *error = Some(err);
+        }
+    }
+
+    fn task_data(dict: &Bound<'_, PyDict>, task: &TaskGroupTask) -> PyResult<()> {
+        dict.set_item("id", &task.status.taskId)?;
+        dict.set_item("name", &task.task.metadata.name)
+    }
+}
+
+impl ProgressObserver for PyProgress {
+    fn tasks_found(&self, count: usize) {
+        self.notify("tasks-found", |dict| dict.set_item("count", count))
+    }
+
+    fn artifacts_found(&self, task: &TaskGroupTask, count: usize) {
+        self.notify("artifacts-found", |dict| {
+            PyProgress::task_data(dict, task)?;
+            dict.set_item("count", count)
+        })
+    }
+
+    fn download_started(&self, dest: &Path, downloaded: u64, total: Option<u64>) {
+        self.notify("download-started", |dict| {
+            dict.set_item("path", dest)?;
+            dict.set_item("downloaded", downloaded)?;
+            dict.set_item("total", total)
+        })
+    }
+
+    fn bytes_downloaded(&self, dest: &Path, bytes: u64) {
+        self.notify("bytes-downloaded", |dict| {
+            dict.set_item("path", dest)?;
+            dict.set_item("bytes", bytes)
+        })
+    }
+
+    fn artifact_finished(
+        &self,
+        task: &TaskGroupTask,
+        artifact: &Artifact,
+        status: &DownloadStatus,
+    ) {
+        self.notify("artifact-finished", |dict| {
+            PyProgress::task_data(dict, task)?;
+            dict.set_item("artifact", &artifact.name)?;
+            dict.set_item("path", status.path())?;
+            dict.set_item("status", status.as_str())?;
+            dict.set_item("error", status.error().map(|err| err.to_string()))
+        })
+    }
+
+    fn task_finished(&self, task: &TaskGroupTask) {
+        self.notify("task-finished", |dict| PyProgress::task_data(dict, task))
+    }
+}
+
+/// Artifact names may be passed as either a single string or a list.
+#[derive(FromPyObject)]
+pub enum ArtifactNames {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ArtifactNames {
+    fn into_selectors(self) -> Result<Vec<tcfetch_rs::ArtifactSelector>, Error> {
+        let names = match self {
+            ArtifactNames::One(name) => vec![name],
+            ArtifactNames::Many(names) => names,
+        };
+        names
+            .iter()
+            .map(|name| tcfetch_rs::ArtifactSelector::new(name).map_err(Error::from))
+            .collect()
+    }
 }
 
 #[pyfunction]
-#[pyo3(signature = (branch, commit, artifact_name=None, taskcluster_base=None, task_filters=None, check_complete=false, out_dir=None, compress=false))]
+#[pyo3(signature = (branch, commit=None, artifact_name=None, taskcluster_base=None, task_filters=None, check_complete=false, out_dir=None, compress=false, retry=None, manifest=None, wait=false, wait_interval=None, wait_timeout=None, credentials_file=None, github_token=None, wait_for_rate_limit=false, check_name=None, config_file=None, task_group=None, task=None, index=None, include_actions=false, duplicates=None, runs=None, filters=None, filter_expr=None, cache=false, cache_dir=None, cache_max_size=None, refresh=false, api_concurrency=None, download_concurrency=None, host_concurrency=None, progress=None, output_encoding=None, compression_level=None, zstd_long_window=None, zstd_dictionary=None, output_template=None))]
+#[allow(clippy::too_many_arguments)]
 pub fn download_artifacts(
     branch: &str,
-    commit: &str,
-    artifact_name: Option<&str>,
+    commit: Option<String>,
+    artifact_name: Option<ArtifactNames>,
     taskcluster_base: Option<&str>,
     task_filters: Option<Vec<String>>,
     check_complete: bool,
     out_dir: Option<&str>,
     compress: bool,
+    retry: Option<RetryPolicy>,
+    manifest: Option<&str>,
+    wait: bool,
+    wait_interval: Option<f64>,
+    wait_timeout: Option<f64>,
+    credentials_file: Option<&str>,
+    github_token: Option<String>,
+    wait_for_rate_limit: bool,
+    check_name: Option<String>,
+    config_file: Option<&str>,
+    task_group: Option<String>,
+    task: Option<String>,
+    index: Option<String>,
+    include_actions: bool,
+    duplicates: Option<&str>,
+    runs: Option<&str>,
+    filters: Option<Vec<String>>,
+    filter_expr: Option<&str>,
+    cache: bool,
+    cache_dir: Option<&str>,
+    cache_max_size: Option<String>,
+    refresh: bool,
+    api_concurrency: Option<usize>,
+    download_concurrency: Option<usize>,
+    host_concurrency: Option<usize>,
+    progress: Option<PyObject>,
+    output_encoding: Option<&str>,
+    compression_level: Option<i32>,
+    zstd_long_window: Option<u32>,
+    zstd_dictionary: Option<PathBuf>,
+    output_template: Option<String>,
 ) -> PyResult<Vec<TaskDownloadData>> {
     let cur_dir = env::current_dir().expect("Invalid working directory");
     let out_path: PathBuf = if let Some(dir) = out_dir {
         cur_dir.join(dir)
     } else {
-        cur_dir
+        cur_dir.clone()
     };
     if !out_path.is_dir() {
         return Err(Error::from(tcfetch_rs::Error::String(format!(
@@ -91,29 +510,129 @@ pub fn download_artifacts(
         .into());
     }
 
-    let task_filters = task_filters
-        .map(|filters| {
-            filters
-                .iter()
-                .map(|filter_str| tcfetch_rs::TaskFilter::new(filter_str).map_err(Error::from))
-                .collect::<Result<Vec<_>, Error>>()
-        })
+    let mut all_filters = Vec::new();
+    for filter_str in task_filters.iter().flatten() {
+        all_filters.push(tcfetch_rs::TaskFilter::new(filter_str).map_err(Error::from)?);
+    }
+    for filter_str in filters.iter().flatten() {
+        all_filters.push(tcfetch_rs::TaskFilter::parse(filter_str).map_err(Error::from)?);
+    }
+    if let Some(expression) = filter_expr {
+        all_filters.push(tcfetch_rs::TaskFilter::expression(expression).map_err(Error::from)?);
+    }
+    let task_filters = (!all_filters.is_empty()).then_some(all_filters);
+    let duplicates = duplicates
+        .map(|value| value.parse().map_err(Error::from))
+        .transpose()?
+        .unwrap_or_default();
+    let runs = runs
+        .map(|value| value.parse().map_err(Error::from))
+        .transpose()?;
+    let artifacts = artifact_name
+        .map(|names| names.into_selectors())
         .transpose()?;
+    if compress && output_encoding.is_some_and(|name| name != "zstd") {
+        return Err(Error::from(tcfetch_rs::Error::String(
+            "compress can only be combined with the zstd output_encoding".into(),
+        ))
+        .into());
+    }
+    let encoding = tcfetch_rs::encoding::OutputEncoding::new(
+        output_encoding.unwrap_or(if compress { "zstd" } else { "raw" }),
+        compression_level,
+        zstd_long_window,
+        zstd_dictionary.map(|path| cur_dir.join(path)),
+    )
+    .map_err(Error::from)?;
+    let output_template = match output_template {
+        Some(template) => Some(template),
+        None => {
+            tcfetch_rs::Config::load(config_file.map(Path::new))
+                .map_err(Error::from)?
+                .output_template
+        }
+    }
+    .map(|template| template.parse().map_err(Error::from))
+    .transpose()?;
+    let wait = if wait {
+        let mut wait = tcfetch_rs::WaitOptions::default();
+        if let Some(interval) = wait_interval {
+            wait.interval = duration(interval)?;
+        }
+        wait.timeout = wait_timeout.map(duration).transpose()?;
+        Some(wait)
+    } else {
+        None
+    };
 
-    Ok(tcfetch_rs::download_artifacts(
+    let mut concurrency = tcfetch_rs::Concurrency::default();
+    if let Some(api) = api_concurrency {
+        concurrency.api = api;
+    }
+    if let Some(downloads) = download_concurrency {
+        concurrency.downloads = downloads;
+    }
+    if let Some(per_host) = host_concurrency {
+        concurrency.per_host = per_host;
+    }
+    let cache_config = cache_config(config_file, cache, cache_dir, cache_max_size)?;
+    let response_cache = cache_config
+        .as_ref()
+        .and_then(|cache_config| cache_config.response_cache())
+        .map(|cache| cache.with_refresh(refresh));
+    let artifact_cache = cache_config
+        .map(|cache_config| cache_config.artifact_cache())
+        .transpose()
+        .map_err(Error::from)?
+        .flatten();
+
+    let progress = progress.map(|callback| {
+        Arc::new(PyProgress {
+            callback,
+            error: Mutex::new(None),
+        })
+    });
+
+    let downloads = tcfetch_rs::download_artifacts(
+        &client(
+            retry,
+            credentials_file,
+            github_token,
+            wait_for_rate_limit,
+            response_cache,
+        )?,
         taskcluster_base,
-        branch,
-        commit,
-        task_filters,
-        artifact_name,
-        check_complete,
+        &project(branch, config_file, check_name)?,
+        &task_source(commit, task_group, task, index)?,
         &out_path,
-        compress,
-    )
-    .map_err(Error::from)?
-    .into_iter()
-    .map(|(task, path)| TaskDownloadData::from_download(task, path))
-    .collect())
+        tcfetch_rs::DownloadOptions {
+            selection: tcfetch_rs::TaskSelection {
+                task_filters,
+                include_actions,
+                duplicates,
+            },
+            artifacts,
+            check_complete,
+            runs,
+            output_template,
+            encoding,
+            manifest: manifest.map(|path| cur_dir.join(path)),
+            wait,
+            cache: artifact_cache,
+            concurrency,
+            progress: progress
+                .clone()
+                .map(|progress| progress as Arc<dyn ProgressObserver>),
+        },
+    );
+    if let Some(err) = progress.and_then(|progress| progress.error.lock().unwrap().take()) {
+        return Err(err);
+    }
+    Ok(downloads
+        .map_err(Error::from)?
+        .into_iter()
+        .map(TaskDownloadData::from_download)
+        .collect())
 }
 
 /// Download artifacts from Taskcluster.
@@ -122,5 +641,7 @@ fn tcfetch(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(download_artifacts, m)?)?;
     m.add_function(wrap_pyfunction!(check_complete, m)?)?;
     m.add_class::<TaskDownloadData>()?;
+    m.add_class::<RetryPolicy>()?;
+    m.add_class::<TestSetting>()?;
     Ok(())
 }
@@ -1,21 +1,126 @@
-from typing import Optional
+from typing import Any, Callable, Literal, Optional
+
+from typing_extensions import deprecated
+
+class TestSetting:
+    os: str
+    os_version: Optional[str]
+    arch: str
+    device: Optional[str]
+    build_type: str
+    build_flags: list[str]
+    runtime: list[str]
+    display_name: str
+    hash: Optional[str]
 
 class TaskDownloadData:
     id: str
     name: str
-    path: str
-    run_id: Optional[str]
+    artifact: Optional[str]
+    path: Optional[str]
+    test_setting_hash: Optional[str]
+    task_run_id: Optional[int]
+    test_setting: Optional[TestSetting]
+    status: Literal[
+        "downloaded",
+        "skipped-existing",
+        "cached",
+        "artifact-missing",
+        "listing-failed",
+        "download-failed",
+        "timed-out",
+    ]
+    error: Optional[str]
+    sha256: Optional[str]
+    @property
+    @deprecated("Use test_setting_hash instead")
+    def run_id(self) -> Optional[str]: ...
+
+class RetryPolicy:
+    max_attempts: int
+    initial_backoff: float
+    max_backoff: float
+    jitter: bool
+    retry_statuses: list[int]
+    honor_retry_after: bool
+
+    def __init__(
+        self,
+        max_attempts: Optional[int] = None,
+        initial_backoff: Optional[float] = None,
+        max_backoff: Optional[float] = None,
+        jitter: Optional[bool] = None,
+        retry_statuses: Optional[list[int]] = None,
+        honor_retry_after: Optional[bool] = None,
+    ) -> None: ...
 
 def check_complete(
-    branch: str, commit: str, taskcluster_base: Optional[str] = None
+    branch: str,
+    commit: Optional[str] = None,
+    taskcluster_base: Optional[str] = None,
+    retry: Optional[RetryPolicy] = None,
+    credentials_file: Optional[str] = None,
+    github_token: Optional[str] = None,
+    wait_for_rate_limit: bool = False,
+    check_name: Optional[str] = None,
+    config_file: Optional[str] = None,
+    task_group: Optional[str] = None,
+    task: Optional[str] = None,
+    index: Optional[str] = None,
 ) -> bool: ...
 def download_artifacts(
     branch: str,
-    commit: str,
-    artifact_name: Optional[str] = None,
+    commit: Optional[str] = None,
+    artifact_name: Optional[str | list[str]] = None,
     taskcluster_base: Optional[str] = None,
-    task_filters: Optional[str] = None,
+    task_filters: Optional[list[str]] = None,
     check_complete: bool = False,
     out_dir: Optional[str] = None,
-    compress: bool = False
+    compress: bool = False,
+    retry: Optional[RetryPolicy] = None,
+    manifest: Optional[str] = None,
+    wait: bool = False,
+    wait_interval: Optional[float] = None,
+    wait_timeout: Optional[float] = None,
+    credentials_file: Optional[str] = None,
+    github_token: Optional[str] = None,
+    wait_for_rate_limit: bool = False,
+    check_name: Optional[str] = None,
+    config_file: Optional[str] = None,
+    task_group: Optional[str] = None,
+    task: Optional[str] = None,
+    index: Optional[str] = None,
+    include_actions: bool = False,
+    duplicates: Optional[Literal["all", "first", "last", "last-successful"]] = None,
+    runs: Optional[Literal["all", "first", "last", "successful", "failed"]] = None,
+    filters: Optional[list[str]] = None,
+    filter_expr: Optional[str] = None,
+    cache: bool = False,
+    cache_dir: Optional[str] = None,
+    cache_max_size: Optional[str] = None,
+    refresh: bool = False,
+    api_concurrency: Optional[int] = None,
+    download_concurrency: Optional[int] = None,
+    host_concurrency: Optional[int] = None,
+    progress: Optional[
+        Callable[
+            [
+                Literal[
+                    "tasks-found",
+                    "artifacts-found",
+                    "download-started",
+                    "bytes-downloaded",
+                    "artifact-finished",
+                    "task-finished",
+                ],
+                dict[str, Any],
+            ],
+            None,
+        ]
+    ] = None,
+    output_encoding: Optional[Literal["raw", "decompressed", "gzip", "zstd"]] = None,
+    compression_level: Optional[int] = None,
+    zstd_long_window: Optional[int] = None,
+    zstd_dictionary: Optional[str] = None,
+    output_template: Optional[str] = None,
 ) -> list[TaskDownloadData]: ...
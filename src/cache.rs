@@ -0,0 +1,806 @@
+use crate::encoding::{Compression, OutputEncoding};
+use crate::utils::{sha256_file, with_suffix};
+use crate::{Error, Result};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Temporary and unreferenced files younger than this are left alone by
+/// `gc`, since another process may still be using them.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Cached responses that haven't been used for this long are removed by `gc`.
+const RESPONSE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How artifacts in the cache are placed in the output directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Use a reflink if the filesystem supports it, otherwise a hardlink,
+    /// otherwise a copy.
+    #[default]
+    Auto,
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+impl LinkMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkMode::Auto => "auto",
+            LinkMode::Reflink => "reflink",
+            LinkMode::Hardlink => "hardlink",
+            LinkMode::Copy => "copy",
+        }
+    }
+}
+
+impl FromStr for LinkMode {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<LinkMode> {
+        Ok(match value {
+            "auto" => LinkMode::Auto,
+            "reflink" => LinkMode::Reflink,
+            "hardlink" => LinkMode::Hardlink,
+            "copy" => LinkMode::Copy,
+            _ => {
+                return Err(Error::String(format!(
+                    "Unknown link mode {} (expected auto, reflink, hardlink or copy)",
+                    value
+                )))
+            }
+        })
+    }
+}
+
+/// Default location of the caches, `$XDG_CACHE_HOME/tcfetch`.
+pub fn default_dir() -> Option<PathBuf> {
+    let cache_dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_dir.join("tcfetch"))
+}
+
+/// Parse a size in bytes, with an optional `K`, `M`, `G` or `T` suffix
+/// (powers of 1024, optionally followed by `iB` or `B`).
+pub fn parse_size(value: &str) -> Result<u64> {
+    let invalid = || Error::String(format!("Invalid size {}", value));
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let suffix = suffix.trim();
+    let unit = suffix
+        .strip_suffix("iB")
+        .or_else(|| suffix.strip_suffix('B'))
+        .unwrap_or(suffix);
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// Identifies an artifact in the cache. Artifacts can't change once the run
+/// that created them has finished, so only artifacts of finished runs are
+/// cached.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheKey {
+    pub task_id: String,
+    pub run_id: u64,
+    pub artifact: String,
+}
+
+impl CacheKey {
+    fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "{}/{}/{}",
+            self.task_id, self.run_id, self.artifact
+        ));
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// Record mapping a `CacheKey` to the object holding the artifact content.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    sha256: String,
+    size: u64,
+}
+
+/// Summary of the contents of the cache.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub dir: PathBuf,
+    /// Number of cached artifacts.
+    pub entries: usize,
+    /// Number of distinct files; identical artifacts share a single file.
+    pub objects: usize,
+    /// Total size in bytes of the cached files.
+    pub size: u64,
+    pub max_size: Option<u64>,
+}
+
+/// What was removed by `ArtifactCache::gc`.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcStats {
+    pub removed_entries: usize,
+    pub removed_objects: usize,
+    /// Bytes freed by removing objects.
+    pub freed: u64,
+    /// Total size in bytes of the objects remaining.
+    pub size: u64,
+}
+
+/// On-disk cache of downloaded artifacts, shared between output directories.
+///
+/// Artifact content is stored once per distinct SHA-256 under `objects/`,
+/// with an entry under `entries/` for each task, run and artifact name. The
+/// modification time of an entry records when it was last used, and the
+/// least recently used entries are evicted when the cache exceeds its
+/// maximum size. Cached files are read-only, so that output files which are
+/// hardlinks to them can't be modified in place.
+#[derive(Debug, Clone)]
+pub struct ArtifactCache {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    link_mode: LinkMode,
+}
+
+impl ArtifactCache {
+    /// Default maximum size of the cache, 10 GiB.
+    pub const DEFAULT_MAX_SIZE: u64 = 10 << 30;
+
+    pub fn new(dir: impl Into<PathBuf>) -> ArtifactCache {
+        ArtifactCache {
+            dir: dir.into(),
+            max_size: Some(ArtifactCache::DEFAULT_MAX_SIZE),
+            link_mode: LinkMode::default(),
+        }
+    }
+
+    /// Set the maximum total size of the cache; `None` for no limit.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> ArtifactCache {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_link_mode(mut self, link_mode: LinkMode) -> ArtifactCache {
+        self.link_mode = link_mode;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub fn link_mode(&self) -> LinkMode {
+        self.link_mode
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.dir.join("entries")
+    }
+
+    fn objects_dir(&self) -> PathBuf {
+        self.dir.join("objects")
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        let digest = key.digest();
+        self.entries_dir()
+            .join(&digest[..2])
+            .join(format!("{}.json", digest))
+    }
+
+    fn object_path(&self, sha256: &str) -> PathBuf {
+        self.objects_dir().join(&sha256[..2]).join(sha256)
+    }
+
+    fn read_entry(&self, path: &Path) -> Result<CacheEntry> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Place the cached artifact for `key` at `dest` in the given encoding,
+    /// returning the SHA-256 of the cached content, or `None` if the
+    /// artifact isn't cached.
+    pub fn get(
+        &self,
+        key: &CacheKey,
+        dest: &Path,
+        encoding: &OutputEncoding,
+        content_type: &str,
+    ) -> Result<Option<String>> {
+        let entry_path = self.entry_path(key);
+        let entry = match self.read_entry(&entry_path) {
+            Ok(entry) => entry,
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                warn!(
+                    "Ignoring invalid cache entry {}: {}",
+                    entry_path.display(),
+                    err
+                );
+                return Ok(None);
+            }
+        };
+        let object = self.object_path(&entry.sha256);
+        if !object.exists() {
+            // Evicted by another process
+            return Ok(None);
+        }
+        File::options()
+            .write(true)
+            .open(&entry_path)?
+            .set_modified(SystemTime::now())?;
+        self.materialize(&object, dest, encoding, content_type)?;
+        Ok(Some(entry.sha256))
+    }
+
+    /// Move the downloaded artifact for `key` from `src` into the cache.
+    pub fn add(&self, key: &CacheKey, src: &Path) -> Result<()> {
+        let sha256 = sha256_file(src)?;
+        let size = src.metadata()?.len();
+        let object = self.object_path(&sha256);
+        if object.exists() {
+            debug!("{} is already in the cache", sha256);
+            fs::remove_file(src)?;
+        } else {
+            fs::create_dir_all(object.parent().expect("Object path has a parent"))?;
+            if let Err(err) = fs::rename(src, &object) {
+                // The cache may be on a different filesystem
+                debug!(
+                    "Moving {} into the cache failed, copying: {}",
+                    src.display(),
+                    err
+                );
+                let tmp_dir = self.tmp_dir();
+                fs::create_dir_all(&tmp_dir)?;
+                let tmp = tmp_dir.join(format!("{}.{}", sha256, process::id()));
+                copy(src, &tmp)?;
+                fs::rename(&tmp, &object)?;
+                fs::remove_file(src)?;
+            }
+            let mut permissions = object.metadata()?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&object, permissions)?;
+        }
+
+        let entry_path = self.entry_path(key);
+        fs::create_dir_all(entry_path.parent().expect("Entry path has a parent"))?;
+        let entry_tmp = with_suffix(&entry_path, ".tmp");
+        let mut writer = BufWriter::new(File::create(&entry_tmp)?);
+        serde_json::to_writer(
+            &mut writer,
+            &CacheEntry {
+                key: key.clone(),
+                sha256,
+                size,
+            },
+        )?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&entry_tmp, &entry_path)?;
+        Ok(())
+    }
+
+    fn materialize(
+        &self,
+        object: &Path,
+        dest: &Path,
+        encoding: &OutputEncoding,
+        content_type: &str,
+    ) -> Result<()> {
+        let tmp = with_suffix(dest, ".cache.tmp");
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
+        }
+        let result = Compression::detect_file(content_type, object).and_then(|existing| {
+            if encoding.keeps(existing) {
+                self.link(object, &tmp)
+            } else {
+                encoding.encode_file(object, content_type, &tmp)
+            }
+        });
+        if let Err(err) = result.and_then(|_| Ok(fs::rename(&tmp, dest)?)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn link(&self, object: &Path, dest: &Path) -> Result<()> {
+        match self.link_mode {
+            LinkMode::Reflink => reflink_copy::reflink(object, dest)?,
+            LinkMode::Hardlink => fs::hard_link(object, dest)?,
+            LinkMode::Copy => copy(object, dest)?,
+            LinkMode::Auto => {
+                if let Err(err) = reflink_copy::reflink(object, dest) {
+                    debug!("Reflink failed, trying a hardlink: {}", err);
+                    if let Err(err) = fs::hard_link(object, dest) {
+                        debug!("Hardlink failed, copying: {}", err);
+                        copy(object, dest)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every entry in the cache, with its path and last use time.
+    fn entries(&self) -> Result<Vec<(PathBuf, SystemTime, Option<CacheEntry>)>> {
+        let mut entries = Vec::new();
+        for path in files(&self.entries_dir())? {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let used = path.metadata()?.modified()?;
+            entries.push((path.clone(), used, self.read_entry(&path).ok()));
+        }
+        Ok(entries)
+    }
+
+    /// Every object in the cache, with its size and modification time.
+    fn objects(&self) -> Result<Vec<(String, u64, SystemTime)>> {
+        let mut objects = Vec::new();
+        for path in files(&self.objects_dir())? {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let metadata = path.metadata()?;
+            objects.push((name.to_owned(), metadata.len(), metadata.modified()?));
+        }
+        Ok(objects)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let objects = self.objects()?;
+        Ok(CacheStats {
+            dir: self.dir.clone(),
+            entries: self.entries()?.len(),
+            objects: objects.len(),
+            size: objects.iter().map(|(_, size, _)| size).sum(),
+            max_size: self.max_size,
+        })
+    }
+
+    /// Remove invalid entries, unreferenced objects and stale temporary
+    /// files, then evict the least recently used entries until the cache
+    /// is no larger than its maximum size.
+    pub fn gc(&self) -> Result<GcStats> {
+        let mut stats = GcStats::default();
+        let now = SystemTime::now();
+        let stale = |time: SystemTime| {
+            now.duration_since(time)
+                .is_ok_and(|age| age > GC_GRACE_PERIOD)
+        };
+
+        for path in files(&self.tmp_dir())? {
+            if stale(path.metadata()?.modified()?) {
+                remove(&path)?;
+            }
+        }
+
+        let mut objects = self
+            .objects()?
+            .into_iter()
+            .map(|(sha256, size, modified)| (sha256, (size, modified)))
+            .collect::<BTreeMap<_, _>>();
+
+        // Valid entries in least recently used order, and the number of
+        // entries referring to each object.
+        let mut entries = Vec::new();
+        let mut refs = BTreeMap::new();
+        for (path, used, entry) in self.entries()? {
+            match entry {
+                Some(entry) if objects.contains_key(&entry.sha256) => {
+                    *refs.entry(entry.sha256.clone()).or_insert(0) += 1;
+                    entries.push((used, path, entry.sha256));
+                }
+                _ => {
+                    remove(&path)?;
+                    stats.removed_entries += 1;
+                }
+            }
+        }
+        entries.sort();
+
+        let mut unused = objects
+            .iter()
+            .filter(|(sha256, (_, modified))| !refs.contains_key(*sha256) && stale(*modified))
+            .map(|(sha256, _)| sha256.clone())
+            .collect::<Vec<_>>();
+
+        // Size of the objects that will remain.
+        let mut size: u64 = refs
+            .keys()
+            .filter_map(|sha256| objects.get(sha256))
+            .map(|(size, _)| size)
+            .sum();
+        let max_size = self.max_size.unwrap_or(u64::MAX);
+        for (_, path, sha256) in entries.iter() {
+            if size <= max_size {
+                break;
+            }
+            remove(path)?;
+            stats.removed_entries += 1;
+            let count = refs.get_mut(sha256).expect("Entry was counted");
+            *count -= 1;
+            if *count == 0 {
+                size -= objects.get(sha256).map(|(size, _)| *size).unwrap_or(0);
+                unused.push(sha256.clone());
+            }
+        }
+
+        for sha256 in unused {
+            if let Some((object_size, _)) = objects.remove(&sha256) {
+                remove(&self.object_path(&sha256))?;
+                stats.removed_objects += 1;
+                stats.freed += object_size;
+            }
+        }
+        stats.size = objects.values().map(|(size, _)| size).sum();
+        if stats.removed_entries > 0 || stats.removed_objects > 0 {
+            info!(
+                "Removed {} entries and {} files ({} bytes) from the cache",
+                stats.removed_entries, stats.removed_objects, stats.freed
+            );
+        }
+        Ok(stats)
+    }
+}
+
+/// A JSON API response stored in the response cache.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CachedResponse {
+    pub url: String,
+    pub etag: Option<String>,
+    /// The response can't change, so may be used without revalidating it.
+    pub immutable: bool,
+    pub body: String,
+}
+
+/// Summary of the contents of the response cache.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseCacheStats {
+    pub dir: PathBuf,
+    pub entries: usize,
+    pub size: u64,
+}
+
+/// On-disk cache of JSON API responses, keyed by URL.
+///
+/// Responses are revalidated with `If-None-Match` before being reused,
+/// unless they are known not to change, e.g. the task listings of a task
+/// group in which every task is resolved.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    refresh: bool,
+}
+
+impl ResponseCache {
+    pub fn new(dir: impl Into<PathBuf>) -> ResponseCache {
+        ResponseCache {
+            dir: dir.into(),
+            refresh: false,
+        }
+    }
+
+    /// Ignore stored responses, replacing them with fresh ones.
+    pub fn with_refresh(mut self, refresh: bool) -> ResponseCache {
+        self.refresh = refresh;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let digest = format!("{:x}", Sha256::digest(url.as_bytes()));
+        self.dir.join(&digest[..2]).join(format!("{}.json", digest))
+    }
+
+    fn read(&self, path: &Path) -> Result<CachedResponse> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    fn write(&self, response: &CachedResponse) -> Result<()> {
+        let path = self.path(&response.url);
+        fs::create_dir_all(path.parent().expect("Response path has a parent"))?;
+        let tmp = with_suffix(&path, &format!(".{}.tmp", fastrand::u32(..)));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, response)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The stored response for `url`, if any.
+    pub(crate) fn get(&self, url: &str) -> Option<CachedResponse> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.path(url);
+        let response = self
+            .read(&path)
+            .ok()
+            .filter(|response| response.url == url)?;
+        if let Err(err) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            debug!("Failed to update {}: {}", path.display(), err);
+        }
+        Some(response)
+    }
+
+    /// Store a response. Responses that can't be revalidated and aren't
+    /// immutable are never reused, so aren't stored.
+    pub(crate) fn put(
+        &self,
+        url: &str,
+        etag: Option<&str>,
+        immutable: bool,
+        body: &str,
+    ) -> Result<()> {
+        if etag.is_none() && !immutable {
+            return Ok(());
+        }
+        self.write(&CachedResponse {
+            url: url.into(),
+            etag: etag.map(|etag| etag.into()),
+            immutable,
+            body: body.into(),
+        })
+    }
+
+    /// Mark the stored response for `url` as immutable, if there is one.
+    pub(crate) fn mark_immutable(&self, url: &str) -> Result<()> {
+        match self.read(&self.path(url)) {
+            Ok(mut response) if response.url == url && !response.immutable => {
+                response.immutable = true;
+                self.write(&response)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn stats(&self) -> Result<ResponseCacheStats> {
+        let mut stats = ResponseCacheStats {
+            dir: self.dir.clone(),
+            entries: 0,
+            size: 0,
+        };
+        for path in files(&self.dir)? {
+            stats.entries += 1;
+            stats.size += path.metadata()?.len();
+        }
+        Ok(stats)
+    }
+
+    /// Remove responses that haven't been used recently. Returns the
+    /// number of responses removed.
+    pub fn gc(&self) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for path in files(&self.dir)? {
+            let max_age = if path.extension().is_some_and(|ext| ext == "tmp") {
+                GC_GRACE_PERIOD
+            } else {
+                RESPONSE_MAX_AGE
+            };
+            let modified = path.metadata()?.modified()?;
+            if now.duration_since(modified).is_ok_and(|age| age > max_age) {
+                remove(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn copy(src: &Path, dest: &Path) -> io::Result<()> {
+    // Not fs::copy, since that would copy the read-only permissions.
+    let mut src = File::open(src)?;
+    let mut dest = File::create(dest)?;
+    io::copy(&mut src, &mut dest)?;
+    Ok(())
+}
+
+/// Remove a file, ignoring it having already been removed by another process.
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The files in `dir` and its immediate subdirectories.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in read_dir {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            for entry in fs::read_dir(entry.path())? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    files.push(entry.path());
+                }
+            }
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn key(task_id: &str) -> CacheKey {
+        CacheKey {
+            task_id: task_id.into(),
+            run_id: 0,
+            artifact: "public/report.json".into(),
+        }
+    }
+
+    /// Add `content` to the cache for `task_id`, last used `age` ago.
+    fn add(cache: &ArtifactCache, task_id: &str, content: &str, age: Duration) -> CacheKey {
+        let key = key(task_id);
+        let src = cache.dir().join(format!("{}.src", task_id));
+        fs::write(&src, content).unwrap();
+        cache.add(&key, &src).unwrap();
+        set_age(&cache.entry_path(&key), age);
+        key
+    }
+
+    fn set_age(path: &Path, age: Duration) {
+        File::open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    fn is_cached(cache: &ArtifactCache, key: &CacheKey) -> bool {
+        cache.entry_path(key).exists()
+    }
+
+    fn new_cache(max_size: Option<u64>) -> (tempfile::TempDir, ArtifactCache) {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = ArtifactCache::new(tmp.path()).with_max_size(max_size);
+        (tmp, cache)
+    }
+
+    #[test]
+    fn gc_evicts_least_recently_used() {
+        let (_tmp, cache) = new_cache(Some(10));
+        let a = add(&cache, "A", "aaaa", 3 * HOUR);
+        let b = add(&cache, "B", "bbbb", 2 * HOUR);
+        let c = add(&cache, "C", "cccc", HOUR);
+        let stats = cache.gc().unwrap();
+        assert!(!is_cached(&cache, &a));
+        assert!(is_cached(&cache, &b));
+        assert!(is_cached(&cache, &c));
+        assert_eq!(stats.removed_entries, 1);
+        assert_eq!(stats.removed_objects, 1);
+        assert_eq!(stats.freed, 4);
+        assert_eq!(stats.size, 8);
+        assert_eq!(cache.stats().unwrap().size, 8);
+
+        // Using an entry makes it the most recently used
+        let dest = cache.dir().join("b.json");
+        cache
+            .get(&b, &dest, &OutputEncoding::Raw, "application/json")
+            .unwrap()
+            .unwrap();
+        add(&cache, "D", "dddd", Duration::ZERO);
+        cache.gc().unwrap();
+        assert!(is_cached(&cache, &b));
+        assert!(!is_cached(&cache, &c));
+    }
+
+    #[test]
+    fn gc_counts_shared_objects_once() {
+        let (_tmp, cache) = new_cache(Some(4));
+        let a1 = add(&cache, "A1", "aaaa", 3 * HOUR);
+        let b = add(&cache, "B", "bbbb", 2 * HOUR);
+        let a2 = add(&cache, "A2", "aaaa", HOUR);
+        assert_eq!(cache.stats().unwrap().objects, 2);
+        let stats = cache.gc().unwrap();
+        // Removing A1 frees nothing while A2 uses the same object
+        assert!(!is_cached(&cache, &a1));
+        assert!(!is_cached(&cache, &b));
+        assert!(is_cached(&cache, &a2));
+        assert_eq!(stats.removed_entries, 2);
+        assert_eq!(stats.removed_objects, 1);
+        assert_eq!(stats.freed, 4);
+        assert_eq!(stats.size, 4);
+    }
+
+    #[test]
+    fn gc_grace_period() {
+        let (_tmp, cache) = new_cache(None);
+        let old = add(&cache, "old", "old", Duration::ZERO);
+        let new = add(&cache, "new", "new", Duration::ZERO);
+        // Objects without entries are only removed once they're stale, since
+        // another process may be about to add an entry for them.
+        for key in [&old, &new] {
+            fs::remove_file(cache.entry_path(key)).unwrap();
+        }
+        set_age(&cache.object_path(&sha256("old")), 2 * HOUR);
+        set_age(&cache.object_path(&sha256("new")), HOUR / 2);
+
+        fs::create_dir_all(cache.tmp_dir()).unwrap();
+        let stale_tmp = cache.tmp_dir().join("stale");
+        let fresh_tmp = cache.tmp_dir().join("fresh");
+        fs::write(&stale_tmp, "").unwrap();
+        fs::write(&fresh_tmp, "").unwrap();
+        set_age(&stale_tmp, 2 * HOUR);
+
+        let invalid = cache.entry_path(&key("invalid"));
+        fs::create_dir_all(invalid.parent().unwrap()).unwrap();
+        fs::write(&invalid, "not json").unwrap();
+
+        let stats = cache.gc().unwrap();
+        assert!(!cache.object_path(&sha256("old")).exists());
+        assert!(cache.object_path(&sha256("new")).exists());
+        assert!(!stale_tmp.exists());
+        assert!(fresh_tmp.exists());
+        assert!(!invalid.exists());
+        assert_eq!(stats.removed_entries, 1);
+        assert_eq!(stats.removed_objects, 1);
+        assert_eq!(stats.freed, 3);
+        assert_eq!(stats.size, 3);
+    }
+
+    fn sha256(content: &str) -> String {
+        format!("{:x}", Sha256::digest(content))
+    }
+}
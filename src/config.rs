@@ -0,0 +1,183 @@
+use crate::cache::{self, parse_size, ArtifactCache, LinkMode, ResponseCache};
+use crate::{Error, GithubProject, HgmoProject, Project, Result};
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Repository definitions, keyed by the name used to refer to them.
+///
+/// The configuration file is TOML with one table per repository, e.g.
+///
+/// ```toml
+/// output-template = "{repo}/{platform}/{label}/{artifact_basename}"
+///
+/// [repos.mozilla-esr128]
+/// vcs = "hg"
+/// path = "releases/mozilla-esr128"
+///
+/// [repos.myproject]
+/// vcs = "github"
+/// owner = "me"
+/// repo = "myproject"
+/// check-name = "Decision Task"
+///
+/// [cache]
+/// dir = "/data/tcfetch-cache"
+/// max-size = "20G"
+/// link = "hardlink"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub repos: BTreeMap<String, Project>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// Template for the paths of fetched artifacts; see `OutputTemplate`.
+    #[serde(default, rename = "output-template")]
+    pub output_template: Option<String>,
+}
+
+/// Settings for the artifact and API response caches. Unset values use the
+/// defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CacheConfig {
+    pub enabled: Option<bool>,
+    /// Directory containing the caches.
+    pub dir: Option<PathBuf>,
+    /// Maximum size of the artifact cache, e.g. `"20G"`; `"none"` for no limit.
+    pub max_size: Option<String>,
+    pub link: Option<LinkMode>,
+}
+
+impl CacheConfig {
+    /// Use the values set in `other`, in preference to those set here.
+    pub fn merge(&mut self, other: CacheConfig) {
+        self.enabled = other.enabled.or(self.enabled);
+        self.dir = other.dir.or(self.dir.take());
+        self.max_size = other.max_size.or(self.max_size.take());
+        self.link = other.link.or(self.link);
+    }
+
+    /// The directory containing the caches, or `None` if caching is
+    /// disabled or there's no cache directory.
+    fn cache_dir(&self) -> Option<PathBuf> {
+        if self.enabled == Some(false) {
+            return None;
+        }
+        self.dir.clone().or_else(cache::default_dir)
+    }
+
+    /// The configured artifact cache, if caching is enabled.
+    pub fn artifact_cache(&self) -> Result<Option<ArtifactCache>> {
+        let Some(dir) = self.cache_dir() else {
+            return Ok(None);
+        };
+        let mut cache =
+            ArtifactCache::new(dir.join("artifacts")).with_link_mode(self.link.unwrap_or_default());
+        if let Some(ref max_size) = self.max_size {
+            cache = cache.with_max_size(match max_size.as_str() {
+                "none" => None,
+                max_size => Some(parse_size(max_size)?),
+            });
+        }
+        Ok(Some(cache))
+    }
+
+    /// The configured API response cache, if caching is enabled.
+    pub fn response_cache(&self) -> Option<ResponseCache> {
+        self.cache_dir()
+            .map(|dir| ResponseCache::new(dir.join("responses")))
+    }
+}
+
+const HGMO_REPOS: &[(&str, &str)] = &[
+    ("try", "try"),
+    ("mozilla-central", "mozilla-central"),
+    ("mozilla-beta", "releases/mozilla-beta"),
+    ("mozilla-release", "releases/mozilla-release"),
+    ("mozilla-esr115", "releases/mozilla-esr115"),
+    ("mozilla-esr128", "releases/mozilla-esr128"),
+    ("mozilla-esr140", "releases/mozilla-esr140"),
+    ("autoland", "integration/autoland"),
+];
+
+impl Default for Config {
+    /// The built-in repository definitions.
+    fn default() -> Self {
+        let mut repos = BTreeMap::new();
+        for (name, path) in HGMO_REPOS {
+            repos.insert(name.to_string(), Project::Hgmo(HgmoProject::new(path)));
+        }
+        repos.insert("wpt".into(), Project::Github(GithubProject::wpt()));
+        Config {
+            repos,
+            cache: CacheConfig::default(),
+            output_template: None,
+        }
+    }
+}
+
+impl Config {
+    /// Default location of the user configuration file,
+    /// `$XDG_CONFIG_HOME/tcfetch/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("tcfetch").join("config.toml"))
+    }
+
+    /// Parse a configuration file.
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let data = fs::read_to_string(path)?;
+        toml::from_str(&data).map_err(|err| {
+            Error::String(format!(
+                "Failed to parse config file {}: {}",
+                path.display(),
+                err
+            ))
+        })
+    }
+
+    /// The built-in definitions, overridden by those in the configuration
+    /// file at `path`, or at the default path if that exists.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let mut config = Config::default();
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Config::default_path().filter(|path| path.exists()),
+        };
+        if let Some(path) = path {
+            config.merge(Config::from_file(&path)?);
+        }
+        Ok(config)
+    }
+
+    /// Add the repositories in `other`, replacing any with the same name,
+    /// and the cache and output settings it sets.
+    pub fn merge(&mut self, other: Config) {
+        self.repos.extend(other.repos);
+        self.cache.merge(other.cache);
+        self.output_template = other.output_template.or(self.output_template.take());
+    }
+
+    /// Resolve a repo spec: the name of a configured repository, or
+    /// `owner/repo` for a GitHub repository using the default settings.
+    pub fn project(&self, spec: &str) -> Result<Project> {
+        if let Some(project) = self.repos.get(spec) {
+            return Ok(project.clone());
+        }
+        if let Some(project) = GithubProject::from_spec(spec) {
+            return Ok(Project::Github(project));
+        }
+        Err(Error::String(format!(
+            "No such repo {} (known repos are {}, or use owner/repo for a GitHub repo)",
+            spec,
+            self.repos.keys().cloned().collect::<Vec<_>>().join(", ")
+        )))
+    }
+}
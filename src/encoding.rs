@@ -0,0 +1,435 @@
+use crate::{Error, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Largest zstd window accepted when decompressing artifacts.
+const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+
+/// Compression applied to an artifact by the task that uploaded it, as
+/// opposed to the Content-Encoding used to serve it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression of content starting with `prefix`. The content
+    /// type is only used if there are too few bytes to tell.
+    pub fn detect(content_type: &str, prefix: &[u8]) -> Option<Compression> {
+        if prefix.starts_with(GZIP_MAGIC) {
+            return Some(Compression::Gzip);
+        }
+        if prefix.starts_with(ZSTD_MAGIC) {
+            return Some(Compression::Zstd);
+        }
+        if prefix.len() >= ZSTD_MAGIC.len() {
+            return None;
+        }
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "application/gzip" | "application/x-gzip" => Some(Compression::Gzip),
+            "application/zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Detect the compression of the file at `path`.
+    pub fn detect_file(content_type: &str, path: &Path) -> Result<Option<Compression>> {
+        let mut prefix = Vec::with_capacity(ZSTD_MAGIC.len());
+        File::open(path)?
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut prefix)?;
+        Ok(Compression::detect(content_type, &prefix))
+    }
+
+    /// Extension of files with this compression, including the leading `.`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Split a compression extension off a file name.
+    fn strip_extension(name: &str) -> &str {
+        [".gz", ".zst", ".zstd"]
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .filter(|stem| !stem.is_empty())
+            .unwrap_or(name)
+    }
+}
+
+/// Settings for writing zstd compressed files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZstdOptions {
+    /// Compression level, from 1 to 22.
+    pub level: i32,
+    /// Enable long distance matching with a window of 2^`long_window`
+    /// bytes. Windows larger than 2^27 bytes need `--long` (or `--memory`)
+    /// to decompress with the zstd command line tool.
+    pub long_window: Option<u32>,
+    /// Dictionary to compress with. The same dictionary is needed to
+    /// decompress the files.
+    pub dictionary: Option<PathBuf>,
+}
+
+impl Default for ZstdOptions {
+    fn default() -> Self {
+        ZstdOptions {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            long_window: None,
+            dictionary: None,
+        }
+    }
+}
+
+/// How artifacts are encoded when written to the output directory. Any
+/// Content-Encoding used to serve an artifact is always removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// The artifact as uploaded, including any compression applied by the
+    /// task, e.g. to a `.json.gz` artifact.
+    #[default]
+    Raw,
+    /// Decompress artifacts that are gzip or zstd compressed.
+    Decompressed,
+    /// Compress with gzip at `level`, from 0 to 9. Artifacts that are
+    /// already gzipped are kept as they are.
+    Gzip { level: u32 },
+    /// Compress with zstd. Artifacts that are already zstd compressed are
+    /// kept as they are.
+    Zstd(ZstdOptions),
+}
+
+impl OutputEncoding {
+    /// Build the encoding called `name` with the given settings, which are
+    /// only valid for the compressed encodings that use them.
+    pub fn new(
+        name: &str,
+        level: Option<i32>,
+        zstd_long_window: Option<u32>,
+        zstd_dictionary: Option<PathBuf>,
+    ) -> Result<OutputEncoding> {
+        let mut encoding = name.parse()?;
+        if (zstd_long_window.is_some() || zstd_dictionary.is_some())
+            && !matches!(encoding, OutputEncoding::Zstd(_))
+        {
+            return Err(Error::String(
+                "zstd options are only valid with the zstd output encoding".into(),
+            ));
+        }
+        match encoding {
+            OutputEncoding::Raw | OutputEncoding::Decompressed if level.is_some() => {
+                return Err(Error::String(format!(
+                    "A compression level isn't valid with the {} output encoding",
+                    name
+                )));
+            }
+            OutputEncoding::Gzip {
+                level: ref mut gzip_level,
+            } => {
+                if let Some(level) = level {
+                    *gzip_level = u32::try_from(level)
+                        .ok()
+                        .filter(|level| *level <= 9)
+                        .ok_or_else(|| {
+                            Error::String(format!(
+                                "Invalid gzip compression level {}; expected 0 to 9",
+                                level
+                            ))
+                        })?;
+                }
+            }
+            OutputEncoding::Zstd(ref mut options) => {
+                if let Some(level) = level {
+                    if !(1..=22).contains(&level) {
+                        return Err(Error::String(format!(
+                            "Invalid zstd compression level {}; expected 1 to 22",
+                            level
+                        )));
+                    }
+                    options.level = level;
+                }
+                if let Some(window_log) = zstd_long_window {
+                    if !(10..=31).contains(&window_log) {
+                        return Err(Error::String(format!(
+                            "Invalid zstd window log {}; expected 10 to 31",
+                            window_log
+                        )));
+                    }
+                }
+                options.long_window = zstd_long_window;
+                options.dictionary = zstd_dictionary;
+            }
+            _ => {}
+        }
+        Ok(encoding)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputEncoding::Raw => "raw",
+            OutputEncoding::Decompressed => "decompressed",
+            OutputEncoding::Gzip { .. } => "gzip",
+            OutputEncoding::Zstd(_) => "zstd",
+        }
+    }
+
+    /// The compression of output files, if they're always compressed.
+    fn compression(&self) -> Option<Compression> {
+        match self {
+            OutputEncoding::Raw | OutputEncoding::Decompressed => None,
+            OutputEncoding::Gzip { .. } => Some(Compression::Gzip),
+            OutputEncoding::Zstd(_) => Some(Compression::Zstd),
+        }
+    }
+
+    /// Name of the output file for an artifact whose file name would
+    /// otherwise be `name`, replacing any compression extension with one
+    /// for the output encoding.
+    pub fn file_name(&self, name: &str) -> String {
+        if *self == OutputEncoding::Raw {
+            return name.to_owned();
+        }
+        let stem = Compression::strip_extension(name);
+        match self.compression() {
+            Some(compression) => format!("{}{}", stem, compression.extension()),
+            None => stem.to_owned(),
+        }
+    }
+
+    /// Whether content with the given existing compression is written
+    /// unchanged.
+    pub fn keeps(&self, existing: Option<Compression>) -> bool {
+        match self {
+            OutputEncoding::Raw => true,
+            OutputEncoding::Decompressed => existing.is_none(),
+            _ => existing == self.compression(),
+        }
+    }
+
+    /// Write the artifact content from `src` to `dest` in this encoding,
+    /// decompressing it first if it's compressed in another format.
+    pub fn encode<R: Read, W: Write>(&self, src: R, content_type: &str, dest: W) -> Result<()> {
+        let mut src = src;
+        let mut prefix = Vec::with_capacity(ZSTD_MAGIC.len());
+        (&mut src)
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut prefix)?;
+        let existing = Compression::detect(content_type, &prefix);
+        let mut src = Cursor::new(prefix).chain(src);
+        let mut dest = dest;
+        if self.keeps(existing) {
+            io::copy(&mut src, &mut dest)?;
+            return Ok(());
+        }
+        let mut content: Box<dyn Read> = match existing {
+            None => Box::new(src),
+            Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(src)),
+            Some(Compression::Zstd) => {
+                let mut decoder = zstd::stream::read::Decoder::new(src)?;
+                decoder.window_log_max(ZSTD_WINDOW_LOG_MAX)?;
+                Box::new(decoder)
+            }
+        };
+        match self {
+            OutputEncoding::Raw | OutputEncoding::Decompressed => {
+                io::copy(&mut content, &mut dest)?;
+            }
+            OutputEncoding::Gzip { level } => {
+                let mut encoder = GzEncoder::new(dest, flate2::Compression::new(*level));
+                io::copy(&mut content, &mut encoder)?;
+                encoder.finish()?;
+            }
+            OutputEncoding::Zstd(options) => {
+                let mut encoder = match options.dictionary {
+                    Some(ref path) => zstd::stream::write::Encoder::with_dictionary(
+                        dest,
+                        options.level,
+                        &fs::read(path)?,
+                    )?,
+                    None => zstd::stream::write::Encoder::new(dest, options.level)?,
+                };
+                if let Some(window_log) = options.long_window {
+                    encoder.long_distance_matching(true)?;
+                    encoder.window_log(window_log)?;
+                }
+                io::copy(&mut content, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the artifact content in the file at `src` to a new file at
+    /// `dest` in this encoding.
+    pub(crate) fn encode_file(&self, src: &Path, content_type: &str, dest: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(dest)?);
+        self.encode(BufReader::new(File::open(src)?), content_type, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl FromStr for OutputEncoding {
+    type Err = Error;
+
+    /// Parse an encoding name, using the default settings for compressed
+    /// encodings.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(OutputEncoding::Raw),
+            "decompressed" => Ok(OutputEncoding::Decompressed),
+            "gzip" => Ok(OutputEncoding::Gzip {
+                level: flate2::Compression::default().level(),
+            }),
+            "zstd" => Ok(OutputEncoding::Zstd(ZstdOptions::default())),
+            _ => Err(Error::String(format!(
+                "Unknown output encoding {}; expected raw, decompressed, gzip or zstd",
+                s
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OutputEncoding::Decompressed;
+
+    const CONTENT: &[u8] = b"{\"results\": [1, 2, 3]}\n";
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(content: &[u8]) -> Vec<u8> {
+        zstd::encode_all(content, 0).unwrap()
+    }
+
+    fn encode(encoding: &OutputEncoding, src: &[u8]) -> Vec<u8> {
+        let mut dest = Vec::new();
+        encoding.encode(src, "application/json", &mut dest).unwrap();
+        dest
+    }
+
+    fn decode(content: &[u8]) -> Vec<u8> {
+        encode(&Decompressed, content)
+    }
+
+    #[test]
+    fn raw() {
+        for src in [CONTENT.to_vec(), gzip(CONTENT), zstd(CONTENT)] {
+            assert_eq!(encode(&OutputEncoding::Raw, &src), src);
+        }
+    }
+
+    #[test]
+    fn decompressed() {
+        for src in [CONTENT.to_vec(), gzip(CONTENT), zstd(CONTENT)] {
+            assert_eq!(encode(&Decompressed, &src), CONTENT);
+        }
+    }
+
+    #[test]
+    fn gzip_output() {
+        let encoding = "gzip".parse::<OutputEncoding>().unwrap();
+        for src in [CONTENT.to_vec(), zstd(CONTENT)] {
+            let output = encode(&encoding, &src);
+            assert!(output.starts_with(GZIP_MAGIC));
+            assert_eq!(decode(&output), CONTENT);
+        }
+        // Content that's already gzipped is kept as it is
+        let src = gzip(CONTENT);
+        assert_eq!(encode(&OutputEncoding::Gzip { level: 9 }, &src), src);
+    }
+
+    #[test]
+    fn zstd_output() {
+        let dictionary = tempfile::NamedTempFile::new().unwrap();
+        fs::write(dictionary.path(), b"results results results").unwrap();
+        let encodings = [
+            "zstd".parse::<OutputEncoding>().unwrap(),
+            OutputEncoding::new("zstd", Some(19), Some(27), None).unwrap(),
+        ];
+        for encoding in encodings.iter() {
+            for src in [CONTENT.to_vec(), gzip(CONTENT)] {
+                let output = encode(encoding, &src);
+                assert!(output.starts_with(ZSTD_MAGIC));
+                assert_eq!(decode(&output), CONTENT);
+            }
+        }
+        let src = zstd(CONTENT);
+        assert_eq!(encode(&encodings[0], &src), src);
+
+        let encoding =
+            OutputEncoding::new("zstd", None, None, Some(dictionary.path().into())).unwrap();
+        let output = encode(&encoding, CONTENT);
+        let dictionary = fs::read(dictionary.path()).unwrap();
+        let mut decoder =
+            zstd::stream::read::Decoder::with_dictionary(&output[..], &dictionary).unwrap();
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, CONTENT);
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(
+            Compression::detect("text/plain", &gzip(CONTENT)[..4]),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::detect("application/gzip", &zstd(CONTENT)[..4]),
+            Some(Compression::Zstd)
+        );
+        // The content type is only used when there are too few bytes to tell
+        assert_eq!(
+            Compression::detect("application/gzip", b"{}"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::detect("application/zstd; charset=binary", b""),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::detect("application/gzip", b"{\"a\""), None);
+        assert_eq!(Compression::detect("application/json", b""), None);
+    }
+
+    #[test]
+    fn file_names() {
+        let gzip = "gzip".parse::<OutputEncoding>().unwrap();
+        let zstd = "zstd".parse::<OutputEncoding>().unwrap();
+        assert_eq!(OutputEncoding::Raw.file_name("log.txt.gz"), "log.txt.gz");
+        assert_eq!(Decompressed.file_name("report.json.gz"), "report.json");
+        assert_eq!(Decompressed.file_name("report.json.zstd"), "report.json");
+        assert_eq!(Decompressed.file_name(".gz"), ".gz");
+        assert_eq!(gzip.file_name("report.json"), "report.json.gz");
+        assert_eq!(gzip.file_name("report.json.zst"), "report.json.gz");
+        assert_eq!(zstd.file_name("report.json.gz"), "report.json.zst");
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(
+            OutputEncoding::new("gzip", Some(1), None, None).unwrap(),
+            OutputEncoding::Gzip { level: 1 }
+        );
+        assert!(OutputEncoding::new("gzip", Some(10), None, None).is_err());
+        assert!(OutputEncoding::new("zstd", Some(0), None, None).is_err());
+        assert!(OutputEncoding::new("zstd", None, Some(32), None).is_err());
+        assert!(OutputEncoding::new("raw", Some(1), None, None).is_err());
+        assert!(OutputEncoding::new("gzip", None, Some(27), None).is_err());
+        assert!("brotli".parse::<OutputEncoding>().is_err());
+    }
+}
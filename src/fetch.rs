@@ -0,0 +1,584 @@
+use crate::cache::{ArtifactCache, CacheKey};
+use crate::encoding::{Compression, OutputEncoding};
+use crate::progress::{DownloadProgress, ProgressObserver};
+use crate::taskcluster::{Artifact, TaskGroupTask, Taskcluster, CHAIN_OF_TRUST_ARTIFACT};
+use crate::template::{OutputTemplate, PathClaims, TemplateValues};
+use crate::utils::{spawn_blocking, unsigned_url, with_suffix, AsyncClient, Concurrency};
+use crate::{ArtifactSelector, Client, DownloadStatus, Error, Result, RunSelection, TaskDownload};
+use futures_util::future::join_all;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Local file name for each artifact, using the artifact's basename unless
+/// that's shared with another artifact selected from the same task.
+fn artifact_file_names<'a>(artifacts: &[&'a Artifact]) -> Vec<(&'a Artifact, String)> {
+    let basename = |artifact: &Artifact| {
+        artifact
+            .name
+            .rsplit('/')
+            .next()
+            .unwrap_or(&artifact.name)
+            .to_owned()
+    };
+    artifacts
+        .iter()
+        .map(|&artifact| {
+            let name = basename(artifact);
+            let unique = artifacts
+                .iter()
+                .filter(|other| basename(other) == name)
+                .count()
+                == 1;
+            let name = if unique {
+                name
+            } else {
+                artifact.name.replace('/', "-")
+            };
+            (artifact, name)
+        })
+        .collect()
+}
+
+/// Key for caching an artifact of a run, or of the latest run if `run_id` is
+/// `None`. Artifacts of runs that haven't finished may still change, so
+/// aren't cached.
+fn cache_key(task: &TaskGroupTask, run_id: Option<u64>, artifact: &Artifact) -> Option<CacheKey> {
+    let run = match run_id {
+        Some(run_id) => task.status.runs.iter().find(|run| run.runId == run_id),
+        None => task.status.runs.last(),
+    }?;
+    run.state.is_complete().then(|| CacheKey {
+        task_id: task.status.taskId.clone(),
+        run_id: run.runId,
+        artifact: artifact.name.clone(),
+    })
+}
+
+/// State shared by the downloads from each task.
+pub(crate) struct ArtifactFetcher<'a> {
+    pub client: &'a Client,
+    pub taskcluster: &'a Taskcluster,
+    pub out_dir: &'a Path,
+    pub artifact_selectors: &'a [ArtifactSelector],
+    pub runs: Option<RunSelection>,
+    pub template: &'a OutputTemplate,
+    pub repo: &'a str,
+    /// The commit the tasks are for, if they were selected by commit.
+    pub commit: Option<&'a str>,
+    pub encoding: &'a OutputEncoding,
+    pub cache: Option<&'a ArtifactCache>,
+    pub concurrency: &'a Concurrency,
+    pub progress: &'a dyn ProgressObserver,
+    /// The output paths used so far.
+    pub paths: PathClaims,
+}
+
+impl ArtifactFetcher<'_> {
+    /// Fetch the selected artifacts of `tasks`, listing and downloading
+    /// artifacts concurrently, and wait for them all to finish.
+    pub(crate) fn fetch(&self, tasks: Vec<TaskGroupTask>) -> Result<Vec<TaskDownload>> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        self.progress.tasks_found(tasks.len());
+        runtime.block_on(async {
+            let client = AsyncClient::new(self.client, self.concurrency)?;
+            let client = &client;
+            for task in tasks.iter() {
+                self.paths.release_task(&task.status.taskId);
+            }
+            let downloads = join_all(tasks.iter().map(|task| async move {
+                let downloads = self.fetch_task_artifacts(client, task).await;
+                self.progress.task_finished(task);
+                downloads
+            }))
+            .await;
+            Ok(downloads.into_iter().flatten().collect())
+        })
+    }
+
+    /// Place the cached `artifact` for `key` at `dest`, returning the
+    /// SHA-256 of its content, or `None` if it isn't cached.
+    async fn get_cached(
+        &self,
+        cache: &ArtifactCache,
+        key: &CacheKey,
+        artifact: &Artifact,
+        dest: &Path,
+    ) -> Result<Option<String>> {
+        let (cache, key, dest, encoding, content_type) = (
+            cache.clone(),
+            key.clone(),
+            dest.to_path_buf(),
+            self.encoding.clone(),
+            artifact.contentType.clone(),
+        );
+        spawn_blocking(move || cache.get(&key, &dest, &encoding, &content_type)).await
+    }
+
+    /// Write the downloaded `artifact` at `src` to `dest` in the output
+    /// encoding, removing `src`.
+    async fn encode(&self, artifact: &Artifact, src: &Path, dest: &Path) -> Result<()> {
+        let (encoding, content_type, src, dest) = (
+            self.encoding.clone(),
+            artifact.contentType.clone(),
+            src.to_path_buf(),
+            dest.to_path_buf(),
+        );
+        spawn_blocking(move || {
+            let existing = Compression::detect_file(&content_type, &src)?;
+            if encoding.keeps(existing) {
+                fs::rename(&src, &dest)?;
+                return Ok(());
+            }
+            let tmp = with_suffix(&dest, ".encode.tmp");
+            let result = encoding
+                .encode_file(&src, &content_type, &tmp)
+                .and_then(|_| Ok(fs::rename(&tmp, &dest)?));
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            fs::remove_file(&src)?;
+            result
+        })
+        .await
+    }
+
+    /// Download `artifact` from `url` to `dest`, returning the SHA-256 of its
+    /// content.
+    async fn download(
+        &self,
+        client: &AsyncClient<'_>,
+        artifact: &Artifact,
+        url: &str,
+        dest: &Path,
+        cache_key: Option<&CacheKey>,
+        expected_sha256: Option<&str>,
+    ) -> Result<String> {
+        let progress = DownloadProgress {
+            observer: self.progress,
+            dest,
+        };
+        // Download to the usual location even when caching, so an
+        // interrupted download can be resumed by a later run.
+        let raw = if *self.encoding == OutputEncoding::Raw {
+            dest.to_path_buf()
+        } else {
+            with_suffix(dest, ".raw")
+        };
+        let sha256 = client
+            .download(&raw, url, expected_sha256, progress)
+            .await?;
+        if let (Some(cache), Some(key)) = (self.cache, cache_key) {
+            let (cache_clone, key_clone, raw_clone) = (cache.clone(), key.clone(), raw.clone());
+            match spawn_blocking(move || cache_clone.add(&key_clone, &raw_clone)).await {
+                Ok(()) => {
+                    return self
+                        .get_cached(cache, key, artifact, dest)
+                        .await?
+                        .ok_or_else(|| {
+                            Error::String(format!(
+                                "{} was removed from the cache before it could be used",
+                                key.artifact
+                            ))
+                        });
+                }
+                Err(err) if raw.exists() => {
+                    warn!("Adding {} to the cache failed: {}", artifact.name, err)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if raw != dest {
+            self.encode(artifact, &raw, dest).await?;
+        }
+        Ok(sha256)
+    }
+
+    async fn fetch_artifact(
+        &self,
+        client: &AsyncClient<'_>,
+        task: &TaskGroupTask,
+        run_id: Option<u64>,
+        artifact: &Artifact,
+        dest: PathBuf,
+        expected_sha256: Option<&str>,
+    ) -> (DownloadStatus, Option<String>) {
+        if dest.exists() {
+            info!("{} exists locally, skipping", dest.to_string_lossy());
+            return (DownloadStatus::SkippedExisting(dest), None);
+        }
+        if let Some(parent) = dest.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                return (DownloadStatus::DownloadFailed(dest, err.into()), None);
+            }
+        }
+        let cache_key = self.cache.and_then(|_| cache_key(task, run_id, artifact));
+        if let (Some(cache), Some(key)) = (self.cache, &cache_key) {
+            match self.get_cached(cache, key, artifact, &dest).await {
+                Ok(Some(sha256)) => {
+                    info!(
+                        "Using cached {} for {}",
+                        artifact.name,
+                        dest.to_string_lossy()
+                    );
+                    return (DownloadStatus::Cached(dest), Some(sha256));
+                }
+                Ok(None) => {}
+                Err(err) => warn!("Reading {} from the cache failed: {}", artifact.name, err),
+            }
+        }
+        let task_id = &task.status.taskId;
+        let download = if artifact.storageType == "object" {
+            self.taskcluster
+                .get_object_download(client, task_id, run_id, artifact)
+                .await
+                .map(|download| (download.url, download.sha256))
+        } else {
+            self.taskcluster
+                .get_log_url(self.client, task_id, run_id, artifact)
+                .map(|url| (url, None))
+        };
+        let (log_url, object_sha256) = match download {
+            Ok(download) => download,
+            Err(err) => return (DownloadStatus::DownloadFailed(dest, err), None),
+        };
+        let expected_sha256 = object_sha256.as_deref().or(expected_sha256);
+
+        info!(
+            "Downloading {} to {}",
+            unsigned_url(&log_url),
+            dest.to_string_lossy()
+        );
+        match self
+            .download(
+                client,
+                artifact,
+                &log_url,
+                &dest,
+                cache_key.as_ref(),
+                expected_sha256,
+            )
+            .await
+        {
+            Ok(sha256) => (DownloadStatus::Downloaded(dest), Some(sha256)),
+            Err(err) => {
+                error!("Downloading {} failed: {}", unsigned_url(&log_url), err);
+                (DownloadStatus::DownloadFailed(dest, err), None)
+            }
+        }
+    }
+
+    /// Record that `dest` is the output path of `artifact`, returning an error
+    /// if it's already the path of another artifact.
+    fn claim_path(
+        &self,
+        dest: &Path,
+        task: &TaskGroupTask,
+        run_id: Option<u64>,
+        artifact: &Artifact,
+    ) -> Option<Error> {
+        let source = match run_id {
+            Some(run_id) => format!(
+                "{} from run {} of task {}",
+                artifact.name, run_id, task.status.taskId
+            ),
+            None => format!("{} from task {}", artifact.name, task.status.taskId),
+        };
+        self.paths
+            .claim(dest, &task.status.taskId, &source)
+            .inspect_err(|err| error!("{}", err))
+            .err()
+    }
+
+    /// SHA-256 hashes from the chain of trust of a run, or of the latest run
+    /// if `run_id` is `None`. Artifacts are downloaded without checking them
+    /// if it can't be fetched.
+    async fn chain_of_trust(
+        &self,
+        client: &AsyncClient<'_>,
+        task: &TaskGroupTask,
+        run_id: Option<u64>,
+    ) -> BTreeMap<String, String> {
+        let Some(run_id) = run_id.or_else(|| task.status.runs.last().map(|run| run.runId)) else {
+            return BTreeMap::new();
+        };
+        self.taskcluster
+            .get_chain_of_trust(client, &task.status.taskId, run_id)
+            .await
+            .unwrap_or_else(|err| {
+                warn!(
+                    "Fetching the chain of trust for task {} failed: {}",
+                    task.status.taskId, err
+                );
+                BTreeMap::new()
+            })
+    }
+
+    /// Fetch the selected artifacts from one run of a task, or from the latest
+    /// run if `run_id` is `None`.
+    async fn fetch_run_artifacts(
+        &self,
+        client: &AsyncClient<'_>,
+        task: &TaskGroupTask,
+        run_id: Option<u64>,
+    ) -> Vec<TaskDownload> {
+        let task_id = &task.status.taskId;
+        let fetched_run_id = run_id.or_else(|| task.status.runs.last().map(|run| run.runId));
+        let task_download = |artifact, status, sha256| TaskDownload {
+            task: task.clone(),
+            run_id: fetched_run_id,
+            artifact,
+            status,
+            sha256,
+        };
+
+        let artifacts = match self
+            .taskcluster
+            .get_artifacts_async(client, task, run_id)
+            .await
+        {
+            Ok(x) => x,
+            Err(err) => {
+                error!("Listing artifacts for task {} failed: {}", task_id, err);
+                return vec![task_download(
+                    None,
+                    DownloadStatus::ListingFailed(err),
+                    None,
+                )];
+            }
+        };
+        let selected = artifacts
+            .iter()
+            .filter(|artifact| {
+                self.artifact_selectors
+                    .iter()
+                    .any(|selector| selector.is_match(&artifact.name))
+            })
+            .collect::<Vec<_>>();
+        if selected.is_empty() {
+            return vec![task_download(None, DownloadStatus::ArtifactMissing, None)];
+        }
+        self.progress.artifacts_found(task, selected.len());
+        let hashes = if artifacts
+            .iter()
+            .any(|artifact| artifact.name == CHAIN_OF_TRUST_ARTIFACT)
+        {
+            self.chain_of_trust(client, task, run_id).await
+        } else {
+            BTreeMap::new()
+        };
+        let test_setting = task.test_setting();
+
+        join_all(artifact_file_names(&selected).into_iter().map(
+            |(artifact, artifact_file_name)| {
+                let mut name = self.template.render(&TemplateValues {
+                    repo: self.repo,
+                    commit: self.commit,
+                    task,
+                    test_setting: test_setting.as_ref(),
+                    run_id: fetched_run_id,
+                    artifact,
+                    artifact_file: &artifact_file_name,
+                });
+                if let Some(file_name) = name.file_name().and_then(|name| name.to_str()) {
+                    name.set_file_name(self.encoding.file_name(file_name));
+                }
+                let dest = self.out_dir.join(name);
+                let collision = self.claim_path(&dest, task, fetched_run_id, artifact);
+                let expected_sha256 = hashes.get(&artifact.name).map(|sha256| sha256.as_str());
+                async move {
+                    let (status, sha256) = match collision {
+                        Some(err) => (DownloadStatus::DownloadFailed(dest, err), None),
+                        None => {
+                            self.fetch_artifact(
+                                client,
+                                task,
+                                run_id,
+                                artifact,
+                                dest,
+                                expected_sha256,
+                            )
+                            .await
+                        }
+                    };
+                    self.progress.artifact_finished(task, artifact, &status);
+                    task_download(Some(artifact.clone()), status, sha256)
+                }
+            },
+        ))
+        .await
+    }
+
+    /// Fetch the selected artifacts from the selected runs of a task, or from
+    /// the latest run if no runs are selected.
+    async fn fetch_task_artifacts(
+        &self,
+        client: &AsyncClient<'_>,
+        task: &TaskGroupTask,
+    ) -> Vec<TaskDownload> {
+        let Some(runs) = self.runs else {
+            return self.fetch_run_artifacts(client, task, None).await;
+        };
+        let run_ids = runs.run_ids(task);
+        if run_ids.is_empty() {
+            return vec![TaskDownload {
+                task: task.clone(),
+                run_id: None,
+                artifact: None,
+                status: DownloadStatus::ArtifactMissing,
+                sha256: None,
+            }];
+        }
+        join_all(
+            run_ids
+                .into_iter()
+                .map(|run_id| self.fetch_run_artifacts(client, task, Some(run_id))),
+        )
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::artifact;
+    use crate::RetryPolicy;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const CONTENT: &[u8] = b"0123456789";
+
+    /// Serve `CONTENT`, closing the connection halfway through the first
+    /// response and honouring range requests after that. Returns the base
+    /// URL and the `Range` header of each request.
+    fn serve_interrupted() -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/artifact", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let requests = ranges.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+                let start = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .map(|range| range.trim_end_matches('-').to_owned());
+                let first = requests.lock().unwrap().is_empty();
+                requests.lock().unwrap().push(start.clone());
+                let response = match start {
+                    Some(start) => {
+                        let start = start.parse::<usize>().unwrap();
+                        let mut response = format!(
+                            "HTTP/1.1 206 Partial Content\r\nETag: \"v1\"\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                            start,
+                            CONTENT.len() - 1,
+                            CONTENT.len(),
+                            CONTENT.len() - start
+                        )
+                        .into_bytes();
+                        response.extend_from_slice(&CONTENT[start..]);
+                        response
+                    }
+                    None => {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\n\r\n",
+                            CONTENT.len()
+                        )
+                        .into_bytes();
+                        let len = if first {
+                            CONTENT.len() / 2
+                        } else {
+                            CONTENT.len()
+                        };
+                        response.extend_from_slice(&CONTENT[..len]);
+                        response
+                    }
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, ranges)
+    }
+
+    #[test]
+    fn resume_with_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out_dir = tmp.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        let cache = ArtifactCache::new(tmp.path().join("cache"));
+        let client = Client::new(RetryPolicy::none());
+        let taskcluster = Taskcluster::new("https://tc.example.com");
+        let template = OutputTemplate::new("{artifact_file}").unwrap();
+        let concurrency = Concurrency::default();
+        let fetcher = ArtifactFetcher {
+            client: &client,
+            taskcluster: &taskcluster,
+            out_dir: &out_dir,
+            artifact_selectors: &[],
+            runs: None,
+            template: &template,
+            repo: "",
+            commit: None,
+            encoding: &OutputEncoding::Raw,
+            cache: Some(&cache),
+            concurrency: &concurrency,
+            progress: &(),
+            paths: PathClaims::default(),
+        };
+        let artifact = artifact("public/artifact.txt");
+        let key = CacheKey {
+            task_id: "T1".into(),
+            run_id: 0,
+            artifact: artifact.name.clone(),
+        };
+        let dest = out_dir.join("artifact.txt");
+        let (url, ranges) = serve_interrupted();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let download = || {
+            runtime.block_on(async {
+                let client = AsyncClient::new(&client, &concurrency)?;
+                fetcher
+                    .download(&client, &artifact, &url, &dest, Some(&key), None)
+                    .await
+            })
+        };
+
+        assert!(download().is_err());
+        assert!(!dest.exists());
+        assert_eq!(
+            fs::read(with_suffix(&dest, ".tmp")).unwrap(),
+            &CONTENT[..CONTENT.len() / 2]
+        );
+
+        download().unwrap();
+        assert_eq!(
+            *ranges.lock().unwrap(),
+            vec![None, Some((CONTENT.len() / 2).to_string())]
+        );
+        assert_eq!(fs::read(&dest).unwrap(), CONTENT);
+        assert!(!with_suffix(&dest, ".tmp").exists());
+
+        // Later fetches use the cache
+        let other = tmp.path().join("other.txt");
+        assert!(cache
+            .get(&key, &other, &OutputEncoding::Raw, &artifact.contentType)
+            .unwrap()
+            .is_some());
+        assert_eq!(fs::read(&other).unwrap(), CONTENT);
+    }
+}
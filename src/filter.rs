@@ -0,0 +1,595 @@
+use crate::taskcluster::{TaskGroupTask, TaskState, TestSetting};
+use crate::{Error, Result};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// A condition on a single task property.
+#[derive(Debug, Clone)]
+enum TaskPredicate {
+    /// The task name matches a regex.
+    Name(Regex),
+    /// The task is in one of the given states.
+    State(Vec<TaskState>),
+    WorkerType(String),
+    ProvisionerId(String),
+    /// The task has a tag, optionally with the given value.
+    Tag(String, Option<String>),
+    /// The value at a JSON pointer into the task's `extra` data exists and is
+    /// truthy, or equals the given value.
+    Extra(String, Option<String>),
+    /// A property of the task's test setting.
+    TestSetting(TestSettingField, String),
+}
+
+#[derive(Debug, Clone)]
+enum TestSettingField {
+    Os,
+    OsVersion,
+    Arch,
+    BuildType,
+    /// The named build variant is enabled.
+    BuildFlag,
+    /// The named runtime variant is enabled.
+    RuntimeFlag,
+}
+
+impl TestSettingField {
+    fn is_match(&self, test_setting: &TestSetting, value: &str) -> bool {
+        let flag = |flags: &BTreeMap<String, bool>| flags.get(value).copied().unwrap_or(false);
+        match self {
+            TestSettingField::Os => test_setting.platform.os.name == value,
+            TestSettingField::OsVersion => {
+                test_setting.platform.os.version.as_deref() == Some(value)
+            }
+            TestSettingField::Arch => test_setting.platform.arch == value,
+            TestSettingField::BuildType => test_setting.build.build_type == value,
+            TestSettingField::BuildFlag => flag(&test_setting.build.flags),
+            TestSettingField::RuntimeFlag => flag(&test_setting.runtime),
+        }
+    }
+}
+
+/// Regex for a task name filter, which is anchored at the start of the name
+/// only if it begins with `^`.
+fn name_regex(re_str: &str) -> Result<Regex> {
+    let anchored = if re_str.starts_with('^') {
+        re_str.to_owned()
+    } else {
+        format!("^.*(?:{})", re_str)
+    };
+    Regex::new(&anchored).map_err(|err| {
+        Error::String(format!(
+            "Filter `{}` can't be parsed as a regular expression: {}",
+            re_str, err
+        ))
+    })
+}
+
+impl TaskPredicate {
+    /// Predicate for a `key` or `key=value` condition.
+    fn from_field(key: &str, value: Option<&str>) -> Result<TaskPredicate> {
+        let required = |value: Option<&str>| {
+            value
+                .map(|value| value.to_string())
+                .ok_or_else(|| Error::String(format!("Filter on {} requires a value", key)))
+        };
+        if let Some(tag) = key.strip_prefix("tag:") {
+            return Ok(TaskPredicate::Tag(
+                tag.into(),
+                value.map(|value| value.into()),
+            ));
+        }
+        if let Some(flag) = key.strip_prefix("build:") {
+            return Ok(TaskPredicate::TestSetting(
+                TestSettingField::BuildFlag,
+                flag.into(),
+            ));
+        }
+        if let Some(flag) = key.strip_prefix("runtime:") {
+            return Ok(TaskPredicate::TestSetting(
+                TestSettingField::RuntimeFlag,
+                flag.into(),
+            ));
+        }
+        if let Some(pointer) = key.strip_prefix("extra:") {
+            if !pointer.starts_with('/') {
+                return Err(Error::String(format!(
+                    "Filter on {} needs a JSON pointer starting with /",
+                    key
+                )));
+            }
+            return Ok(TaskPredicate::Extra(
+                pointer.into(),
+                value.map(|value| value.into()),
+            ));
+        }
+        Ok(match key {
+            "name" => TaskPredicate::Name(name_regex(&required(value)?)?),
+            "state" => TaskPredicate::State(
+                required(value)?
+                    .split(',')
+                    .map(|state| state.parse())
+                    .collect::<Result<Vec<TaskState>>>()?,
+            ),
+            "worker-type" => TaskPredicate::WorkerType(required(value)?),
+            "provisioner-id" => TaskPredicate::ProvisionerId(required(value)?),
+            "os" => TaskPredicate::TestSetting(TestSettingField::Os, required(value)?),
+            "os-version" => {
+                TaskPredicate::TestSetting(TestSettingField::OsVersion, required(value)?)
+            }
+            "arch" => TaskPredicate::TestSetting(TestSettingField::Arch, required(value)?),
+            "build-type" => {
+                TaskPredicate::TestSetting(TestSettingField::BuildType, required(value)?)
+            }
+            _ => {
+                return Err(Error::String(format!(
+                    "Unknown filter field `{}`, expected name, state, worker-type, provisioner-id, os, os-version, arch, build-type, build:<variant>, runtime:<variant>, tag:<name> or extra:<pointer>",
+                    key
+                )))
+            }
+        })
+    }
+
+    fn is_match(&self, task: &TaskGroupTask) -> bool {
+        match self {
+            TaskPredicate::Name(re) => re.is_match(&task.task.metadata.name),
+            TaskPredicate::State(states) => states.contains(&task.status.state),
+            TaskPredicate::WorkerType(worker_type) => &task.status.workerType == worker_type,
+            TaskPredicate::ProvisionerId(provisioner_id) => {
+                &task.status.provisionerId == provisioner_id
+            }
+            TaskPredicate::Tag(key, value) => match (task.task.tags.get(key), value) {
+                (Some(tag_value), Some(value)) => tag_value == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+            TaskPredicate::Extra(pointer, value) => {
+                let extra = serde_json::to_value(&task.task.extra).unwrap_or_default();
+                match (extra.pointer(pointer), value) {
+                    (Some(serde_json::Value::String(found)), Some(value)) => found == value,
+                    (Some(found), Some(value)) => &found.to_string() == value,
+                    (Some(found), None) => !matches!(
+                        found,
+                        serde_json::Value::Null | serde_json::Value::Bool(false)
+                    ),
+                    (None, _) => false,
+                }
+            }
+            TaskPredicate::TestSetting(field, value) => task
+                .test_setting()
+                .is_some_and(|test_setting| field.is_match(&test_setting, value)),
+        }
+    }
+}
+
+/// Boolean combination of task predicates.
+#[derive(Debug, Clone)]
+enum FilterExpr {
+    Predicate(TaskPredicate),
+    Not(Box<FilterExpr>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    fn is_match(&self, task: &TaskGroupTask) -> bool {
+        match self {
+            FilterExpr::Predicate(predicate) => predicate.is_match(task),
+            FilterExpr::Not(expr) => !expr.is_match(task),
+            FilterExpr::And(exprs) => exprs.iter().all(|expr| expr.is_match(task)),
+            FilterExpr::Or(exprs) => exprs.iter().any(|expr| expr.is_match(task)),
+        }
+    }
+
+    fn not(self) -> FilterExpr {
+        FilterExpr::Not(Box::new(self))
+    }
+}
+
+/// Selects tasks. Filters are created from a regex applied to the task name
+/// (`TaskFilter::new`), a `key=value` condition on other task properties
+/// (`TaskFilter::parse`), or a boolean expression combining both
+/// (`TaskFilter::expression`).
+#[derive(Debug, Clone)]
+pub struct TaskFilter {
+    expr: FilterExpr,
+}
+
+impl TaskFilter {
+    pub fn new(filter_str: &str) -> Result<TaskFilter> {
+        let invert = filter_str.starts_with('!');
+        let re_str = if invert { &filter_str[1..] } else { filter_str };
+        let mut expr = FilterExpr::Predicate(TaskPredicate::Name(name_regex(re_str)?));
+        if invert {
+            expr = expr.not();
+        }
+        Ok(TaskFilter { expr })
+    }
+
+    /// Parse a condition on a task property, optionally prefixed with `!` to
+    /// exclude matching tasks:
+    ///
+    /// * `name=<regex>` - as for `TaskFilter::new`
+    /// * `state=<state>[,<state>...]` - the task is in one of the states
+    /// * `worker-type=<worker type>`
+    /// * `provisioner-id=<provisioner id>`
+    /// * `tag:<name>[=<value>]` - the task has the tag, with the given value
+    /// * `extra:<JSON pointer>[=<value>]` - the value at the pointer into the
+    ///   task's `extra` data equals the given value, or is present and not
+    ///   `false` or `null`
+    /// * `os=<name>`, `os-version=<version>`, `arch=<arch>`,
+    ///   `build-type=<type>` - properties of the task's test setting
+    /// * `build:<variant>`, `runtime:<variant>` - the build or runtime variant
+    ///   is enabled in the task's test setting
+    pub fn parse(filter_str: &str) -> Result<TaskFilter> {
+        let invert = filter_str.starts_with('!');
+        let spec = if invert { &filter_str[1..] } else { filter_str };
+        let (key, value) = match spec.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (spec, None),
+        };
+        let mut expr = FilterExpr::Predicate(TaskPredicate::from_field(key, value)?);
+        if invert {
+            expr = expr.not();
+        }
+        Ok(TaskFilter { expr })
+    }
+
+    /// Parse a boolean filter expression, e.g.
+    /// `(linux or mac) and not asan and state=completed`.
+    ///
+    /// Expressions combine conditions with `and` (or `&&`), `or` (or `||`),
+    /// `not` (or `!`) and parentheses; `and` binds more tightly than `or`.
+    /// Conditions are `key=value` or `key!=value` comparisons as for
+    /// `TaskFilter::parse`, `tag:`, `extra:`, `build:` or `runtime:` tests,
+    /// or a bare regex matched against the task name as for `TaskFilter::new`.
+    /// Values containing spaces or punctuation can be quoted with `"` or `'`.
+    pub fn expression(expression: &str) -> Result<TaskFilter> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            expression,
+            tokens,
+            pos: 0,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(parser.error(token.start, "Expected `and`, `or` or end of expression"));
+        }
+        Ok(TaskFilter { expr })
+    }
+
+    pub(crate) fn is_match(&self, task: &TaskGroupTask) -> bool {
+        self.expr.is_match(task)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Eq,
+    NotEq,
+    /// A bare word or quoted string.
+    Word(String),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    /// Character offset of the start of the token.
+    start: usize,
+}
+
+fn parse_error(expression: &str, position: usize, message: &str) -> Error {
+    Error::FilterExpression {
+        expression: expression.into(),
+        position,
+        message: message.into(),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | '=' | '!' | '"' | '\'' | '&' | '|')
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    let chars = expression.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let start = pos;
+        let c = chars[pos];
+        let next = chars.get(pos + 1).copied();
+        let kind = match c {
+            c if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            '=' => TokenKind::Eq,
+            '!' if next == Some('=') => {
+                pos += 1;
+                TokenKind::NotEq
+            }
+            '!' => TokenKind::Not,
+            '&' if next == Some('&') => {
+                pos += 1;
+                TokenKind::And
+            }
+            '|' if next == Some('|') => {
+                pos += 1;
+                TokenKind::Or
+            }
+            '&' | '|' => {
+                return Err(parse_error(
+                    expression,
+                    start,
+                    &format!("Unexpected `{}`, did you mean `{}{}`?", c, c, c),
+                ))
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                pos += 1;
+                loop {
+                    match chars.get(pos) {
+                        None => return Err(parse_error(expression, start, "Unterminated string")),
+                        Some(&quote) if quote == c => break,
+                        Some('\\') if chars.get(pos + 1) == Some(&c) => {
+                            value.push(c);
+                            pos += 2;
+                        }
+                        Some(&other) => {
+                            value.push(other);
+                            pos += 1;
+                        }
+                    }
+                }
+                TokenKind::Word(value)
+            }
+            _ => {
+                while pos < chars.len() && is_word_char(chars[pos]) {
+                    pos += 1;
+                }
+                let word = chars[start..pos].iter().collect::<String>();
+                tokens.push(Token {
+                    kind: match word.as_str() {
+                        "and" => TokenKind::And,
+                        "or" => TokenKind::Or,
+                        "not" => TokenKind::Not,
+                        _ => TokenKind::Word(word),
+                    },
+                    start,
+                });
+                continue;
+            }
+        };
+        pos += 1;
+        tokens.push(Token { kind, start });
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next_if(&mut self, kind: &TokenKind) -> bool {
+        if self.peek().is_some_and(|token| &token.kind == kind) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, position: usize, message: &str) -> Error {
+        parse_error(self.expression, position, message)
+    }
+
+    /// Position at which the expression ends.
+    fn end(&self) -> usize {
+        self.expression.chars().count()
+    }
+
+    fn parse_or(&mut self) -> Result<FilterExpr> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.next_if(&TokenKind::Or) {
+            exprs.push(self.parse_and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            FilterExpr::Or(exprs)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<FilterExpr> {
+        let mut exprs = vec![self.parse_not()?];
+        while self.next_if(&TokenKind::And) {
+            exprs.push(self.parse_not()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            FilterExpr::And(exprs)
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<FilterExpr> {
+        if self.next_if(&TokenKind::Not) {
+            return Ok(self.parse_not()?.not());
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> Result<FilterExpr> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error(self.end(), "Unexpected end of expression"));
+        };
+        self.pos += 1;
+        match token.kind {
+            TokenKind::LParen => {
+                let expr = self.parse_or()?;
+                if !self.next_if(&TokenKind::RParen) {
+                    let position = self.peek().map_or(self.end(), |token| token.start);
+                    return Err(self.error(position, "Expected `)`"));
+                }
+                Ok(expr)
+            }
+            TokenKind::Word(word) => self.parse_condition(word, token.start),
+            _ => Err(self.error(token.start, "Expected a condition")),
+        }
+    }
+
+    fn parse_condition(&mut self, word: String, start: usize) -> Result<FilterExpr> {
+        let negate = match self.peek().map(|token| &token.kind) {
+            Some(TokenKind::Eq) => false,
+            Some(TokenKind::NotEq) => true,
+            _ => {
+                // A bare word is a name regex, unless it's a presence test
+                let predicate = if ["tag:", "extra:", "build:", "runtime:"]
+                    .iter()
+                    .any(|prefix| word.starts_with(prefix))
+                {
+                    TaskPredicate::from_field(&word, None)
+                } else {
+                    name_regex(&word).map(TaskPredicate::Name)
+                };
+                return predicate
+                    .map(FilterExpr::Predicate)
+                    .map_err(|err| self.error(start, &err.to_string()));
+            }
+        };
+        self.pos += 1;
+        let value = match self.peek().cloned() {
+            Some(Token {
+                kind: TokenKind::Word(value),
+                ..
+            }) => {
+                self.pos += 1;
+                value
+            }
+            Some(token) => return Err(self.error(token.start, "Expected a value")),
+            None => return Err(self.error(self.end(), "Expected a value")),
+        };
+        let expr = TaskPredicate::from_field(&word, Some(&value))
+            .map(FilterExpr::Predicate)
+            .map_err(|err| self.error(start, &err.to_string()))?;
+        Ok(if negate { expr.not() } else { expr })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::task;
+
+    fn matches(expression: &str, name: &str) -> bool {
+        TaskFilter::expression(expression)
+            .unwrap()
+            .is_match(&task(name))
+    }
+
+    /// Position and message of the error from parsing `expression`.
+    fn parse_error(expression: &str) -> (usize, String) {
+        match TaskFilter::expression(expression) {
+            Err(Error::FilterExpression {
+                position, message, ..
+            }) => (position, message),
+            other => panic!("Expected a parse error for {}, got {:?}", expression, other),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        // not binds more tightly than and, which binds more tightly than or
+        let expression = "linux or mac and not asan";
+        assert!(matches(expression, "test-linux-asan"));
+        assert!(matches(expression, "test-mac"));
+        assert!(!matches(expression, "test-mac-asan"));
+        assert!(!matches(expression, "test-windows"));
+
+        assert!(matches("not linux and mac", "test-mac"));
+        assert!(!matches("not linux and mac", "test-linux-mac"));
+        assert!(matches("linux && mac || windows", "test-windows"));
+        assert!(!matches("linux && mac || windows", "test-linux"));
+        assert!(matches("!!linux", "test-linux"));
+    }
+
+    #[test]
+    fn parentheses() {
+        let expression = "(linux or mac) and not asan";
+        assert!(matches(expression, "test-linux"));
+        assert!(!matches(expression, "test-linux-asan"));
+        assert!(!matches("not (linux or mac)", "test-mac"));
+        assert!(matches("not (linux or mac)", "test-windows"));
+        assert!(matches("((linux))", "test-linux"));
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(matches("name=linux", "test-linux"));
+        assert!(!matches("name!=linux", "test-linux"));
+        assert!(matches("state=completed,failed", "test-linux"));
+        assert!(!matches("state!=completed", "test-linux"));
+        assert!(matches("^test-", "test-linux"));
+        assert!(!matches("^linux", "test-linux"));
+        assert!(matches("name='test linux'", "test linux"));
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(parse_error("(linux or mac"), (13, "Expected `)`".into()));
+        assert_eq!(
+            parse_error("linux mac"),
+            (6, "Expected `and`, `or` or end of expression".into())
+        );
+        assert_eq!(
+            parse_error("linux and"),
+            (9, "Unexpected end of expression".into())
+        );
+        assert_eq!(
+            parse_error("linux and )"),
+            (10, "Expected a condition".into())
+        );
+        assert_eq!(parse_error("name= and"), (6, "Expected a value".into()));
+        assert_eq!(parse_error("a and 'b"), (6, "Unterminated string".into()));
+        let (position, message) = parse_error("linux or bogus=1");
+        assert_eq!(position, 9);
+        assert!(message.starts_with("Unknown filter field `bogus`"));
+    }
+
+    #[test]
+    fn pipe_needs_quotes() {
+        assert_eq!(
+            parse_error("linux|mac"),
+            (5, "Unexpected `|`, did you mean `||`?".into())
+        );
+        assert_eq!(
+            parse_error("a & b"),
+            (2, "Unexpected `&`, did you mean `&&`?".into())
+        );
+        assert!(matches("\"linux|mac\"", "test-mac"));
+        assert!(matches("name='linux|mac' and not asan", "test-linux"));
+    }
+
+    #[test]
+    fn invalid_regex() {
+        let err = TaskFilter::new("linux(").unwrap_err().to_string();
+        assert!(err.starts_with("Filter `linux(` can't be parsed as a regular expression: "));
+        let (position, message) = parse_error("mac or 'linux('");
+        assert_eq!(position, 7);
+        assert!(message.starts_with("Filter `linux(` can't be parsed"));
+    }
+}
@@ -1,8 +1,14 @@
-use crate::utils::{get_json, url};
-use crate::Result;
-use reqwest;
+use crate::utils::{check_status, get_cached_json, now, url, CachePolicy, Client};
+use crate::{Error, Result};
+use log::warn;
+use reqwest::blocking::Response;
+use reqwest::header::IF_NONE_MATCH;
+use reqwest::{StatusCode, Url};
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
 use std::collections::BTreeMap;
+use std::thread::sleep;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct ChecksResponse {
@@ -135,12 +141,79 @@ pub struct Repo {
     pub name: String,
 }
 
-pub fn get_checks(
-    client: &reqwest::blocking::Client,
-    owner: &str,
-    repo: &str,
-    sha1: &str,
-) -> Result<Vec<CheckRun>> {
+const USER_AGENT: &str = concat!("tcfetch/", env!("CARGO_PKG_VERSION"));
+
+/// Error for a response indicating that the primary or secondary rate
+/// limit was exceeded, if any.
+fn rate_limit_error(resp: &Response, authenticated: bool) -> Option<Error> {
+    let status = resp.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    let reset = if let Some(retry_after) = header("retry-after") {
+        now() + retry_after
+    } else if header("x-ratelimit-remaining") == Some(0) {
+        header("x-ratelimit-reset").unwrap_or_else(|| now() + 60)
+    } else {
+        return None;
+    };
+    Some(Error::GithubRateLimit {
+        limit: header("x-ratelimit-limit"),
+        reset,
+        authenticated,
+    })
+}
+
+fn get_json<T>(client: &Client, url: &str, query: &[(&str, String)]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let url = Url::parse_with_params(url, query)
+        .map_err(|err| Error::String(format!("Invalid url {}: {}", url, err)))?;
+    loop {
+        // Conditional requests answered with 304 Not Modified don't count
+        // against the rate limit.
+        let result = client.with_retry(url.as_str(), || {
+            get_cached_json(client, url.as_str(), CachePolicy::Revalidate, |etag| {
+                let mut req = client
+                    .get(url.as_str())
+                    .header("User-Agent", USER_AGENT)
+                    .header("Accept", "application/vnd.github+json")
+                    .header("X-GitHub-Api-Version", "2022-11-28");
+                if let Some(token) = client.github_token() {
+                    req = req.bearer_auth(token);
+                }
+                if let Some(etag) = etag {
+                    req = req.header(IF_NONE_MATCH, etag);
+                }
+                let resp = req.send()?;
+                if let Some(err) = rate_limit_error(&resp, client.github_token().is_some()) {
+                    return Err(err);
+                }
+                check_status(resp)
+            })
+        });
+        match result {
+            Err(Error::GithubRateLimit { reset, .. }) if client.wait_for_github_rate_limit() => {
+                let wait = reset.saturating_sub(now()) + 1;
+                warn!(
+                    "GitHub API rate limit exceeded, waiting {}s for it to reset",
+                    wait
+                );
+                sleep(Duration::from_secs(wait));
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn get_checks(client: &Client, owner: &str, repo: &str, sha1: &str) -> Result<Vec<CheckRun>> {
     let url_suffix = format!("repos/{}/{}/commits/{}/check-runs", owner, repo, sha1);
     let mut page = 0;
     let mut checks = Vec::new();
@@ -148,28 +221,13 @@ pub fn get_checks(
     while checks_total.is_none() || checks_total != Some(checks.len() as u64) {
         page += 1;
         let base_url = &url("https://api.github.com/", &url_suffix);
-        let mut query = vec![
-            ("per_page".into(), "100".into()),
-            ("filter".into(), "all".into()),
-        ];
+        let mut query = vec![("per_page", "100".into()), ("filter", "all".into())];
         if page > 1 {
-            query.push(("page".into(), page.to_string()));
+            query.push(("page", page.to_string()));
         }
-        let checks_resp: ChecksResponse = get_json(
-            client,
-            base_url,
-            Some(query),
-            Some(vec![
-                ("user-agent".to_string(), "tcfetch/0.4".to_string()),
-                (
-                    "Accept".to_string(),
-                    "application/vnd.github+json".to_string(),
-                ),
-                ("X-GitHub-Api-Version".to_string(), "2022-11-28".to_string()),
-            ]),
-        )?;
+        let checks_resp: ChecksResponse = get_json(client, base_url, &query)?;
         checks_total = Some(checks_resp.total_count);
-        checks.extend(checks_resp.check_runs.into_iter())
+        checks.extend(checks_resp.check_runs)
     }
     Ok(checks)
 }
@@ -0,0 +1,141 @@
+use crate::taskcluster::{Taskcluster, TaskclusterCI};
+use crate::{gh, Client, TaskFilter};
+use crate::{Error, Result};
+use serde_derive::Deserialize;
+
+const COMMUNITY_TC: &str = "https://community-tc.services.mozilla.com";
+
+fn default_check_name() -> String {
+    "Decision Task".into()
+}
+
+fn default_artifact_name() -> String {
+    "live_backing.log".into()
+}
+
+fn default_taskcluster_base() -> String {
+    COMMUNITY_TC.into()
+}
+
+/// A GitHub repository whose CI runs on Taskcluster through the
+/// Taskcluster-GitHub integration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GithubProject {
+    pub owner: String,
+    pub repo: String,
+    /// Name of the check run created for the decision task.
+    #[serde(default = "default_check_name")]
+    pub check_name: String,
+    /// Task filters used when none are given explicitly.
+    #[serde(default)]
+    pub task_filters: Vec<String>,
+    /// Artifact fetched when no artifact name is given explicitly.
+    #[serde(default = "default_artifact_name")]
+    pub artifact_name: String,
+    /// Root URL of the Taskcluster deployment running the tasks.
+    #[serde(default = "default_taskcluster_base", rename = "taskcluster-url")]
+    pub taskcluster_base: String,
+}
+
+impl GithubProject {
+    /// A project using the defaults of the Taskcluster-GitHub integration.
+    pub fn new(owner: &str, repo: &str) -> GithubProject {
+        GithubProject {
+            owner: owner.into(),
+            repo: repo.into(),
+            check_name: default_check_name(),
+            task_filters: Vec::new(),
+            artifact_name: default_artifact_name(),
+            taskcluster_base: default_taskcluster_base(),
+        }
+    }
+
+    /// The web-platform-tests/wpt project.
+    pub fn wpt() -> GithubProject {
+        GithubProject {
+            check_name: "wpt-decision-task".into(),
+            task_filters: vec!["-chrome-|-firefox-".into()],
+            artifact_name: "wpt_report.json.gz".into(),
+            ..GithubProject::new("web-platform-tests", "wpt")
+        }
+    }
+
+    /// Parse an `owner/repo` spec, returning `None` if it isn't one.
+    pub fn from_spec(spec: &str) -> Option<GithubProject> {
+        let (owner, repo) = spec.split_once('/')?;
+        if owner.is_empty() || repo.is_empty() || repo.contains('/') {
+            return None;
+        }
+        Some(GithubProject::new(owner, repo))
+    }
+}
+
+pub(crate) struct GithubCI {
+    project: GithubProject,
+    taskcluster: Taskcluster,
+}
+
+impl GithubCI {
+    pub(crate) fn new(project: GithubProject, taskcluster_base: Option<&str>) -> Self {
+        let taskcluster = Taskcluster::new(taskcluster_base.unwrap_or(&project.taskcluster_base));
+        GithubCI {
+            project,
+            taskcluster,
+        }
+    }
+}
+
+impl TaskclusterCI for GithubCI {
+    fn taskcluster(&self) -> &Taskcluster {
+        &self.taskcluster
+    }
+
+    fn default_task_filter(&self) -> Result<Vec<TaskFilter>> {
+        self.project
+            .task_filters
+            .iter()
+            .map(|filter| TaskFilter::new(filter))
+            .collect()
+    }
+
+    fn default_artifact_name(&self) -> &str {
+        &self.project.artifact_name
+    }
+
+    fn get_taskgroups(
+        &self,
+        client: &Client,
+        commit: &str,
+        include_actions: bool,
+    ) -> Result<Vec<String>> {
+        if include_actions {
+            return Err(Error::String(
+                "Action task groups are only supported for hg.mozilla.org repos".into(),
+            ));
+        }
+        let check_runs = gh::get_checks(client, &self.project.owner, &self.project.repo, commit)?;
+        let mut task_names = vec![];
+        for check in check_runs.iter() {
+            if check.name == self.project.check_name {
+                if let Some(ref details_url) = check.details_url {
+                    if let Some(task_name) = details_url.rsplit('/').next().map(|x| x.to_string()) {
+                        task_names.push(task_name);
+                    }
+                } else {
+                    return Err(Error::String(format!(
+                        "No details_url for {} check; can't find taskgroup",
+                        self.project.check_name
+                    )));
+                }
+            }
+        }
+        if task_names.is_empty() {
+            return Err(Error::String(format!(
+                "Unable to find decision task (no {} check for {}/{} commit {})",
+                self.project.check_name, self.project.owner, self.project.repo, commit
+            )));
+        }
+        Ok(task_names)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::taskcluster::Credentials;
+use crate::utils::now;
+use crate::{Error, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha2::Sha256;
+
+/// The `ext` field, which carries the certificate for temporary credentials.
+fn ext(credentials: &Credentials) -> Result<Option<String>> {
+    let Some(ref certificate) = credentials.certificate else {
+        return Ok(None);
+    };
+    let certificate: serde_json::Value = serde_json::from_str(certificate)?;
+    let ext = serde_json::json!({ "certificate": certificate });
+    Ok(Some(STANDARD.encode(serde_json::to_string(&ext)?)))
+}
+
+fn mac(
+    credentials: &Credentials,
+    kind: &str,
+    ts: u64,
+    nonce: &str,
+    method: &str,
+    url: &Url,
+    ext: Option<&str>,
+) -> Result<String> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::String(format!("Can't sign url without a host: {}", url)))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| Error::String(format!("Can't sign url without a port: {}", url)))?;
+    let resource = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    };
+    let ext = ext.unwrap_or("").replace('\\', "\\\\").replace('\n', "\\n");
+    let normalized = format!(
+        "hawk.1.{}\n{}\n{}\n{}\n{}\n{}\n{}\n\n{}\n",
+        kind,
+        ts,
+        nonce,
+        method.to_uppercase(),
+        resource,
+        host.to_lowercase(),
+        port,
+        ext
+    );
+    let mut mac = Hmac::<Sha256>::new_from_slice(credentials.accessToken.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(normalized.as_bytes());
+    Ok(STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+/// Value of the Authorization header for a request.
+pub(crate) fn authorization_header(
+    credentials: &Credentials,
+    method: &str,
+    url: &Url,
+) -> Result<String> {
+    let nonce = (0..8).map(|_| fastrand::alphanumeric()).collect::<String>();
+    header(
+        credentials,
+        method,
+        url,
+        now(),
+        &nonce,
+        ext(credentials)?.as_deref(),
+    )
+}
+
+fn header(
+    credentials: &Credentials,
+    method: &str,
+    url: &Url,
+    ts: u64,
+    nonce: &str,
+    ext: Option<&str>,
+) -> Result<String> {
+    let mac = mac(credentials, "header", ts, nonce, method, url, ext)?;
+    let mut header = format!(
+        r#"Hawk id="{}", ts="{}", nonce="{}", mac="{}""#,
+        credentials.clientId, ts, nonce, mac
+    );
+    if let Some(ext) = ext {
+        header.push_str(&format!(r#", ext="{}""#, ext));
+    }
+    Ok(header)
+}
+
+/// A copy of `url` with a `bewit` parameter that authorizes GET requests
+/// for `ttl_secs` seconds.
+pub(crate) fn signed_url(credentials: &Credentials, url: &Url, ttl_secs: u64) -> Result<Url> {
+    let bewit = bewit(
+        credentials,
+        url,
+        now() + ttl_secs,
+        ext(credentials)?.as_deref(),
+    )?;
+    let mut url = url.clone();
+    url.query_pairs_mut().append_pair("bewit", &bewit);
+    Ok(url)
+}
+
+/// The `bewit` parameter authorizing GET requests for `url` until `exp`.
+fn bewit(credentials: &Credentials, url: &Url, exp: u64, ext: Option<&str>) -> Result<String> {
+    let mac = mac(credentials, "bewit", exp, "", "GET", url, ext)?;
+    Ok(URL_SAFE_NO_PAD.encode(format!(
+        "{}\\{}\\{}\\{}",
+        credentials.clientId,
+        exp,
+        mac,
+        ext.unwrap_or_default()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(id: &str, key: &str) -> Credentials {
+        Credentials {
+            clientId: id.into(),
+            accessToken: key.into(),
+            certificate: None,
+        }
+    }
+
+    // Test vectors from the Hawk specification and reference implementation.
+
+    #[test]
+    fn header_mac() {
+        let credentials = credentials(
+            "dh37fgj492je",
+            "werxhqb98rpaxn39848xrunpaw3489ruxnpa98w4rxn",
+        );
+        let url = Url::parse("http://example.com:8000/resource/1?b=1&a=2").unwrap();
+        assert_eq!(
+            header(
+                &credentials,
+                "GET",
+                &url,
+                1353832234,
+                "j4h3g2",
+                Some("some-app-ext-data")
+            )
+            .unwrap(),
+            r#"Hawk id="dh37fgj492je", ts="1353832234", nonce="j4h3g2", mac="6R4rV5iE+NPoym+WwjeHzjAGXUtLNIxmo1vpMofpLAE=", ext="some-app-ext-data""#
+        );
+    }
+
+    #[test]
+    fn bewit_mac() {
+        let credentials = credentials("123456", "2983d45yun89q");
+        let url = Url::parse("https://example.com/somewhere/over/the/rainbow").unwrap();
+        assert_eq!(
+            bewit(&credentials, &url, 1356420707, Some("xandyandz")).unwrap(),
+            "MTIzNDU2XDEzNTY0MjA3MDdca3NjeHdOUjJ0SnBQMVQxekRMTlBiQjVVaUtJVTl0T1NKWFRVZEc3WDloOD1ceGFuZHlhbmR6"
+        );
+    }
+
+    #[test]
+    fn certificate_ext() {
+        let mut credentials = credentials("id", "key");
+        assert_eq!(ext(&credentials).unwrap(), None);
+        credentials.certificate = Some(r#"{"version": 1, "seed": "abc"}"#.into());
+        let ext = STANDARD
+            .decode(ext(&credentials).unwrap().unwrap())
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&ext).unwrap(),
+            serde_json::json!({"certificate": {"version": 1, "seed": "abc"}})
+        );
+    }
+}
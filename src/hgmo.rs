@@ -1,9 +1,10 @@
-use crate::taskcluster::{IndexResponse, Taskcluster, TaskclusterCI};
-use crate::utils::{get_json, url};
+use crate::taskcluster::{Taskcluster, TaskclusterCI};
+use crate::utils::{get_json, Client};
 use crate::{Error, Result, TaskFilter};
 use serde_derive::Deserialize;
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct Revision {
     pub node: String,
     pub desc: String,
@@ -14,55 +15,158 @@ pub struct Revision {
     pub pushuser: String,
 }
 
-fn hg_path(repo: &str) -> Option<&'static str> {
-    match repo {
-        "try" => Some("try"),
-        "mozilla-release" => Some("releases/mozilla-release"),
-        "mozilla-beta" => Some("releases/mozilla-beta"),
-        "mozilla-central" => Some("mozilla-central"),
-        "mozilla-inbound" => Some("integration/mozilla-inbound"),
-        "autoland" => Some("integration/autoland"),
-        _ => None,
+const FIREFOX_CI_TC: &str = "https://firefox-ci-tc.services.mozilla.com";
+
+fn default_index() -> String {
+    "gecko.v2.{project}.revision.{revision}.taskgraph.decision".into()
+}
+
+fn default_task_filters() -> Vec<String> {
+    vec!["-web-platform-tests-|-spidermonkey-".into()]
+}
+
+fn default_artifact_name() -> String {
+    "wptreport.json".into()
+}
+
+fn default_actions_index() -> String {
+    "gecko.v2.{project}.revision.{revision}.taskgraph.actions".into()
+}
+
+fn default_git2hg() -> String {
+    "https://lando.moz.tools/api/git2hg/firefox/{revision}".into()
+}
+
+fn default_taskcluster_base() -> String {
+    FIREFOX_CI_TC.into()
+}
+
+/// A repository on hg.mozilla.org whose decision tasks are found through
+/// the Taskcluster index.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HgmoProject {
+    /// Path of the repository on hg.mozilla.org e.g. `integration/autoland`.
+    pub path: String,
+    /// Index path of the decision task. `{project}` is replaced by the last
+    /// component of the repository path and `{revision}` by the full commit hash.
+    #[serde(default = "default_index")]
+    pub index: String,
+    /// Index namespace under which action tasks are indexed, with the same
+    /// replacements as `index`.
+    #[serde(default = "default_actions_index")]
+    pub actions_index: String,
+    /// URL returning the hg revision for a git commit, as `{"hg_hash": ...}`.
+    /// `{revision}` is replaced by the git commit hash. An empty string
+    /// disables looking up git commits.
+    #[serde(default = "default_git2hg")]
+    pub git2hg: String,
+    /// Task filters used when none are given explicitly.
+    #[serde(default = "default_task_filters")]
+    pub task_filters: Vec<String>,
+    /// Artifact fetched when no artifact name is given explicitly.
+    #[serde(default = "default_artifact_name")]
+    pub artifact_name: String,
+    /// Root URL of the Taskcluster deployment running the tasks.
+    #[serde(default = "default_taskcluster_base", rename = "taskcluster-url")]
+    pub taskcluster_base: String,
+}
+
+impl HgmoProject {
+    /// A Gecko repository at `path`, using the Firefox CI defaults.
+    pub fn new(path: &str) -> HgmoProject {
+        HgmoProject {
+            path: path.into(),
+            index: default_index(),
+            actions_index: default_actions_index(),
+            git2hg: default_git2hg(),
+            task_filters: default_task_filters(),
+            artifact_name: default_artifact_name(),
+            taskcluster_base: default_taskcluster_base(),
+        }
+    }
+
+    /// Project name, as used in the Taskcluster index.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
     }
 }
 
 pub(crate) struct HgmoCI {
     taskcluster: Taskcluster,
-    repo: String,
-    hg_path: &'static str,
+    project: HgmoProject,
 }
 
 impl HgmoCI {
-    pub(crate) fn for_repo(taskcluster_base: Option<&str>, repo: String) -> Option<Self> {
-        hg_path(&repo).map(|hg_path| HgmoCI {
-            taskcluster: Taskcluster::new(
-                taskcluster_base.unwrap_or("https://firefox-ci-tc.services.mozilla.com"),
-            ),
-            repo,
-            hg_path,
-        })
+    pub(crate) fn new(project: HgmoProject, taskcluster_base: Option<&str>) -> Self {
+        HgmoCI {
+            taskcluster: Taskcluster::new(taskcluster_base.unwrap_or(&project.taskcluster_base)),
+            project,
+        }
     }
 
-    fn expand_revision(
-        &self,
-        client: &reqwest::blocking::Client,
-        commit: &str,
-    ) -> Result<Option<String>> {
+    fn expand_revision(&self, client: &Client, commit: &str) -> Result<Option<String>> {
         let url_ = format!(
             "https://hg.mozilla.org/{}/json-rev/{}",
-            self.hg_path, commit
+            self.project.path, commit
         );
 
-        let resp =
-            get_json::<Revision>(client, &url_, None, None).map(|revision| Some(revision.node));
-        if let Err(Error::Reqwest(ref err)) = resp {
-            if let Some(status_code) = err.status() {
-                if status_code == reqwest::StatusCode::NOT_FOUND {
-                    return Ok(None);
-                }
-            }
+        not_found_as_none(
+            get_json::<Revision>(client, &url_, None, None).map(|revision| revision.node),
+        )
+    }
+
+    /// Map a git commit to the corresponding hg revision.
+    fn git_to_hg(&self, client: &Client, commit: &str) -> Result<Option<String>> {
+        if self.project.git2hg.is_empty() {
+            return Ok(None);
         }
-        resp
+        let url_ = self.project.git2hg.replace("{revision}", commit);
+        not_found_as_none(get_json::<Git2Hg>(client, &url_, None, None).map(|resp| resp.hg_hash))
+    }
+
+    /// Find the decision task for a full revision hash in the index.
+    fn find_decision_task(&self, client: &Client, revision: &str) -> Result<Option<String>> {
+        let index = self
+            .project
+            .index
+            .replace("{project}", self.project.name())
+            .replace("{revision}", revision);
+        not_found_as_none(
+            self.taskcluster
+                .find_indexed_task(client, &index)
+                .map(|resp| resp.taskId),
+        )
+    }
+
+    /// Find the action tasks for a full revision hash in the index. Each
+    /// action task's id is the id of the task group containing the tasks it
+    /// created.
+    fn find_action_tasks(&self, client: &Client, revision: &str) -> Result<Vec<String>> {
+        let namespace = self
+            .project
+            .actions_index
+            .replace("{project}", self.project.name())
+            .replace("{revision}", revision);
+        let tasks = not_found_as_none(self.taskcluster.list_indexed_tasks(client, &namespace))?;
+        Ok(tasks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|task| task.taskId)
+            .collect())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Git2Hg {
+    hg_hash: String,
+}
+
+fn not_found_as_none<T>(resp: Result<T>) -> Result<Option<T>> {
+    match resp {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::Status { status, .. }) if status == reqwest::StatusCode::NOT_FOUND => Ok(None),
+        Err(err) => Err(err),
     }
 }
 
@@ -70,24 +174,32 @@ fn commit_is_valid(commit: &str) -> bool {
     commit.len() >= 12 && commit.len() <= 40
 }
 
+fn is_full_hash(commit: &str) -> bool {
+    commit.len() == 40 && commit.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 impl TaskclusterCI for HgmoCI {
     fn taskcluster(&self) -> &Taskcluster {
         &self.taskcluster
     }
 
-    fn default_task_filter(&self) -> Vec<TaskFilter> {
-        vec![TaskFilter::new("-web-platform-tests-|-spidermonkey-")
-            .expect("Invalid default task filter")]
+    fn default_task_filter(&self) -> Result<Vec<TaskFilter>> {
+        self.project
+            .task_filters
+            .iter()
+            .map(|filter| TaskFilter::new(filter))
+            .collect()
     }
 
-    fn default_artifact_name(&self) -> &'static str {
-        "wptreport.json"
+    fn default_artifact_name(&self) -> &str {
+        &self.project.artifact_name
     }
 
     fn get_taskgroups(
         &self,
-        client: &reqwest::blocking::Client,
+        client: &Client,
         commit: &str,
+        include_actions: bool,
     ) -> Result<Vec<String>> {
         if !commit_is_valid(commit) {
             return Err(Error::String(format!(
@@ -96,22 +208,34 @@ impl TaskclusterCI for HgmoCI {
             )));
         }
 
-        let commit = self
-            .expand_revision(client, commit)?
-            .ok_or_else(|| Error::String(format!("No such revision {}", commit)))?;
+        let revision = match self.expand_revision(client, commit)? {
+            Some(revision) => revision,
+            None if is_full_hash(commit) => {
+                // Not an hg revision, so this may be a git commit. Newer
+                // tasks are indexed by git commit, older ones only by the
+                // corresponding hg revision.
+                if self.find_decision_task(client, commit)?.is_some() {
+                    commit.to_string()
+                } else {
+                    self.git_to_hg(client, commit)?
+                        .ok_or_else(|| Error::String(format!("No such revision {}", commit)))?
+                }
+            }
+            None => {
+                return Err(Error::String(format!(
+                    "No such revision {} (git commits must be given as a full 40 character hash)",
+                    commit
+                )))
+            }
+        };
 
-        let index = format!(
-            "gecko.v2.{}.revision.{}.taskgraph.decision",
-            self.repo, commit
-        );
-        Ok(vec![
-            get_json::<IndexResponse>(
-                client,
-                &url(&self.taskcluster.index_base, &format!("task/{}", index)),
-                None,
-                None,
-            )?
-            .taskId,
-        ])
+        let task_id = self.find_decision_task(client, &revision)?.ok_or_else(|| {
+            Error::String(format!("No decision task found for revision {}", revision))
+        })?;
+        let mut taskgroups = vec![task_id];
+        if include_actions {
+            taskgroups.extend(self.find_action_tasks(client, &revision)?);
+        }
+        Ok(taskgroups)
     }
 }
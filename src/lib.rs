@@ -1,17 +1,48 @@
+pub mod cache;
+pub mod config;
+pub mod encoding;
+mod fetch;
+mod filter;
 pub mod gh;
-mod ghwpt;
+mod github;
+mod hawk;
 mod hgmo;
+pub mod manifest;
+pub mod progress;
 pub mod taskcluster;
+pub mod template;
+#[cfg(test)]
+mod test_util;
 mod utils;
+pub mod verify;
 
-use log::{error, info};
+use cache::ArtifactCache;
+pub use config::Config;
+use encoding::OutputEncoding;
+use fetch::ArtifactFetcher;
+pub use filter::TaskFilter;
+use futures_util::future::join_all;
+pub use github::GithubProject;
+pub use hgmo::HgmoProject;
+use log::{info, warn};
+use manifest::Manifest;
+use progress::ProgressObserver;
 use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use taskcluster::{tasks_complete, TaskGroupTask, Taskcluster, TaskclusterCI};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use taskcluster::{tasks_complete, Artifact, TaskGroupTask, TaskRun, TaskState, TaskclusterCI};
+use template::{OutputTemplate, PathClaims};
 use thiserror::Error;
-use utils::download;
+use utils::AsyncClient;
+pub use utils::{Client, Concurrency, RetryPolicy};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -23,178 +54,932 @@ pub enum Error {
     Serde(#[from] serde_json::Error),
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error("HTTP status {status} for url ({url})")]
+    Status {
+        url: String,
+        status: reqwest::StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("{}", github_rate_limit_message(*.limit, *.reset, *.authenticated))]
+    GithubRateLimit {
+        /// Number of requests allowed per hour, if known.
+        limit: Option<u64>,
+        /// Unix time at which the rate limit resets.
+        reset: u64,
+        /// Whether the requests were authenticated with a token.
+        authenticated: bool,
+    },
+    #[error("{}", filter_expression_message(.expression, *.position, .message))]
+    FilterExpression {
+        expression: String,
+        /// Character offset of the error in the expression.
+        position: usize,
+        message: String,
+    },
     #[error("{0}")]
     String(String),
 }
 
-fn fetch_job_logs(
-    client: &reqwest::blocking::Client,
-    taskcluster: &Taskcluster,
-    out_dir: &Path,
-    tasks: Vec<TaskGroupTask>,
-    artifact_name: &str,
-    compress: bool,
-) -> Vec<(TaskGroupTask, PathBuf)> {
-    let mut pool = scoped_threadpool::Pool::new(8);
-    let paths = Arc::new(Mutex::new(Vec::with_capacity(tasks.len())));
-
-    // TODO: Convert this to async
-    pool.scoped(|scope| {
-        for task in tasks.into_iter() {
-            scope.execute(|| {
-                let task_id = task.status.taskId.clone();
-                let client = client.clone();
-
-                let artifacts = match taskcluster.get_artifacts(&client, &task_id) {
-                    Ok(x) => x,
-                    Err(err) => {
-                        error!("{}", err);
-                        return;
-                    }
-                };
-                // TODO: this selects too many artifacts, should split on separator and check for an exact match
-                let artifact = artifacts
-                    .iter()
-                    .find(|&artifact| artifact.name.ends_with(artifact_name));
-                if let Some(artifact) = artifact {
-                    let ext = if compress { ".zstd" } else { "" };
-
-                    let name = PathBuf::from(format!(
-                        "{}-{}-{}{}",
-                        task.task.metadata.name.replace('/', "-"),
-                        &task.status.taskId,
-                        artifact_name,
-                        ext
-                    ));
-                    let dest = out_dir.join(name);
-
-                    if dest.exists() {
-                        info!("{} exists locally, skipping", dest.to_string_lossy());
-                    } else {
-                        let log_url = taskcluster.get_log_url(&task_id, artifact);
-
-                        info!("Downloading {} to {}", log_url, dest.to_string_lossy());
-                        if !download(&client, &dest, &log_url, compress) {
-                            error!("Downloading {} failed", { log_url })
-                        }
-                    }
-                    {
-                        let mut paths = paths.lock().unwrap();
-                        (*paths).push((task, dest));
-                    }
-                }
-            });
+fn filter_expression_message(expression: &str, position: usize, message: &str) -> String {
+    format!(
+        "{} at position {} in filter expression\n  {}\n  {}^",
+        message,
+        position + 1,
+        expression,
+        " ".repeat(position)
+    )
+}
+
+fn github_rate_limit_message(limit: Option<u64>, reset: u64, authenticated: bool) -> String {
+    let now = utils::now();
+    let limit = limit
+        .map(|limit| format!(" of {} requests per hour", limit))
+        .unwrap_or_default();
+    let hint = if authenticated {
+        ""
+    } else {
+        ". Set GITHUB_TOKEN to authenticate, which allows a much higher limit"
+    };
+    format!(
+        "GitHub API rate limit{} exceeded; it resets in {}s{}",
+        limit,
+        reset.saturating_sub(now),
+        hint
+    )
+}
+
+/// Outcome of fetching a single artifact from a task.
+#[derive(Debug)]
+pub enum DownloadStatus {
+    /// The artifact was downloaded to the given path.
+    Downloaded(PathBuf),
+    /// The artifact already existed at the given path, so wasn't downloaded again.
+    SkippedExisting(PathBuf),
+    /// The task doesn't have a matching artifact.
+    ArtifactMissing,
+    /// Listing the task's artifacts failed.
+    ListingFailed(Error),
+    /// The artifact was copied or linked to the given path from the cache.
+    Cached(PathBuf),
+    /// Downloading the artifact to the given path failed.
+    DownloadFailed(PathBuf, Error),
+    /// The task still wasn't complete when waiting for it timed out.
+    TimedOut(Error),
+}
+
+impl DownloadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadStatus::Downloaded(_) => "downloaded",
+            DownloadStatus::SkippedExisting(_) => "skipped-existing",
+            DownloadStatus::ArtifactMissing => "artifact-missing",
+            DownloadStatus::Cached(_) => "cached",
+            DownloadStatus::ListingFailed(_) => "listing-failed",
+            DownloadStatus::DownloadFailed(_, _) => "download-failed",
+            DownloadStatus::TimedOut(_) => "timed-out",
         }
-    });
-    Arc::try_unwrap(paths).unwrap().into_inner().unwrap()
+    }
+
+    /// The error that caused the fetch to fail, if any.
+    pub fn error(&self) -> Option<&Error> {
+        match self {
+            DownloadStatus::ListingFailed(err)
+            | DownloadStatus::DownloadFailed(_, err)
+            | DownloadStatus::TimedOut(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Path to the local file, if the artifact is available locally.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            DownloadStatus::Downloaded(path)
+            | DownloadStatus::SkippedExisting(path)
+            | DownloadStatus::Cached(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Whether this outcome means the fetch is incomplete.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            DownloadStatus::ListingFailed(_)
+                | DownloadStatus::DownloadFailed(_, _)
+                | DownloadStatus::TimedOut(_)
+        )
+    }
+}
+
+/// Result of fetching one artifact from a task. Tasks without a matching
+/// artifact, or whose artifacts couldn't be listed, have a single entry with
+/// no artifact.
+#[derive(Debug)]
+pub struct TaskDownload {
+    pub task: TaskGroupTask,
+    /// The run the artifact is from.
+    pub run_id: Option<u64>,
+    pub artifact: Option<Artifact>,
+    pub status: DownloadStatus,
+    /// SHA-256 of the artifact content before it was encoded for output, if
+    /// it was downloaded or taken from the cache.
+    pub sha256: Option<String>,
+}
+
+/// Which runs of each task to fetch artifacts from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunSelection {
+    /// Every run.
+    All,
+    /// The first run.
+    First,
+    /// The last run.
+    Last,
+    /// Runs that completed successfully.
+    Successful,
+    /// Runs that failed or ended with an exception.
+    Failed,
+}
+
+impl RunSelection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunSelection::All => "all",
+            RunSelection::First => "first",
+            RunSelection::Last => "last",
+            RunSelection::Successful => "successful",
+            RunSelection::Failed => "failed",
+        }
+    }
+
+    /// Ids of the selected runs of a task.
+    fn run_ids(&self, task: &TaskGroupTask) -> Vec<u64> {
+        let runs = &task.status.runs;
+        let selected: Vec<&TaskRun> = match self {
+            RunSelection::All => runs.iter().collect(),
+            RunSelection::First => runs.first().into_iter().collect(),
+            RunSelection::Last => runs.last().into_iter().collect(),
+            RunSelection::Successful => runs
+                .iter()
+                .filter(|run| run.state == TaskState::Completed)
+                .collect(),
+            RunSelection::Failed => runs
+                .iter()
+                .filter(|run| matches!(run.state, TaskState::Failed | TaskState::Exception))
+                .collect(),
+        };
+        selected.iter().map(|run| run.runId).collect()
+    }
 }
 
+impl FromStr for RunSelection {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<RunSelection> {
+        match value {
+            "all" => Ok(RunSelection::All),
+            "first" => Ok(RunSelection::First),
+            "last" => Ok(RunSelection::Last),
+            "successful" => Ok(RunSelection::Successful),
+            "failed" => Ok(RunSelection::Failed),
+            _ => Err(Error::String(format!(
+                "Unknown run selection {}, expected all, first, last, successful or failed",
+                value
+            ))),
+        }
+    }
+}
+
+/// Whether a task matches all of the task filters.
 fn include_task(task: &TaskGroupTask, task_filters: &[TaskFilter]) -> bool {
-    let name = &task.task.metadata.name;
-    task_filters.iter().all(|filter| filter.is_match(name))
+    task_filters.iter().all(|filter| filter.is_match(task))
 }
 
-#[derive(Debug)]
-pub struct TaskFilter {
-    filter_re: Regex,
-    invert: bool,
-}
-
-impl TaskFilter {
-    pub fn new(filter_str: &str) -> Result<TaskFilter> {
-        let invert = filter_str.starts_with('!');
-        let mut re_str = if invert { &filter_str[1..] } else { filter_str };
-        let filter_string: String;
-        if !re_str.starts_with('^') {
-            filter_string = format!("^.*(?:{})", re_str);
-            re_str = &filter_string
-        }
-        Regex::new(re_str)
-            .map(|filter_re| TaskFilter { filter_re, invert })
+/// Selects artifacts by name.
+///
+/// A selector starting with `/` must match the full artifact name (without
+/// the leading `/`). Otherwise it's matched against the trailing path
+/// segments of the artifact name, so `wptreport.json` matches
+/// `public/test_info/wptreport.json` but not `public/test_info/mywptreport.json`.
+/// Selectors may contain the glob wildcards `*` and `?`, which don't match
+/// `/`, `**`, which does, and `[...]` character classes.
+#[derive(Debug, Clone)]
+pub struct ArtifactSelector {
+    selector: String,
+    re: Regex,
+}
+
+impl ArtifactSelector {
+    pub fn new(selector: &str) -> Result<ArtifactSelector> {
+        let (anchored, pattern) = match selector.strip_prefix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, selector),
+        };
+        let prefix = if anchored { "^" } else { "^(?:.*/)?" };
+        let re_str = format!("{}{}$", prefix, glob_to_regex(pattern)?);
+        Regex::new(&re_str)
+            .map(|re| ArtifactSelector {
+                selector: selector.into(),
+                re,
+            })
             .map_err(|_| {
                 Error::String(format!(
-                    "Filter `{}` can't be parsed as a regular expression",
-                    filter_str
+                    "Artifact name `{}` can't be parsed as a glob pattern",
+                    selector
                 ))
             })
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.selector
+    }
+
     pub(crate) fn is_match(&self, name: &str) -> bool {
-        let mut is_match = self.filter_re.is_match(name);
-        if self.invert {
-            is_match = !is_match;
+        self.re.is_match(name)
+    }
+}
+
+fn glob_to_regex(pattern: &str) -> Result<String> {
+    let mut re_str = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re_str.push_str(".*");
+            }
+            '*' => re_str.push_str("[^/]*"),
+            '?' => re_str.push_str("[^/]"),
+            '[' => {
+                re_str.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re_str.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) if c == '\\' || c == '[' => {
+                            re_str.push('\\');
+                            re_str.push(c)
+                        }
+                        Some(c) => re_str.push(c),
+                        None => {
+                            return Err(Error::String(format!(
+                                "Unterminated character class in `{}`",
+                                pattern
+                            )))
+                        }
+                    }
+                }
+                re_str.push(']');
+            }
+            c => re_str.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Ok(re_str)
+}
+
+/// A repository whose CI runs on Taskcluster.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "vcs", rename_all = "lowercase")]
+pub enum Project {
+    /// A repository on hg.mozilla.org.
+    #[serde(rename = "hg")]
+    Hgmo(HgmoProject),
+    /// A repository on GitHub using the Taskcluster-GitHub integration.
+    Github(GithubProject),
+}
+
+impl Project {
+    /// Resolve a repo spec using the built-in repository definitions.
+    pub fn from_spec(spec: &str) -> Result<Project> {
+        Config::default().project(spec)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Project::Hgmo(project) => project.name().into(),
+            Project::Github(project) => format!("{}/{}", project.owner, project.repo),
+        }
+    }
+}
+
+fn get_repo_ci(project: &Project, taskcluster_base: Option<&str>) -> Box<dyn TaskclusterCI> {
+    match project {
+        Project::Hgmo(project) => Box::new(hgmo::HgmoCI::new(project.clone(), taskcluster_base)),
+        Project::Github(project) => {
+            Box::new(github::GithubCI::new(project.clone(), taskcluster_base))
+        }
+    }
+}
+
+/// Where to find the tasks to operate on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TaskSource {
+    /// The tasks for a commit in the repository.
+    Commit(String),
+    /// All the tasks in a task group.
+    TaskGroup(String),
+    /// A single task.
+    Task(String),
+    /// The task at an index path. If this is a decision task, all the
+    /// tasks in its task group.
+    Index(String),
+}
+
+impl fmt::Display for TaskSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskSource::Commit(commit) => write!(f, "commit {}", commit),
+            TaskSource::TaskGroup(task_group_id) => write!(f, "task group {}", task_group_id),
+            TaskSource::Task(task_id) => write!(f, "task {}", task_id),
+            TaskSource::Index(index_path) => write!(f, "index path {}", index_path),
+        }
+    }
+}
+
+/// Which of several tasks with the same label to use.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DuplicateTasks {
+    /// Use every task.
+    #[default]
+    All,
+    /// Use the earliest created task.
+    First,
+    /// Use the most recently created task.
+    Last,
+    /// Use the most recently created task that completed successfully, or
+    /// the most recently created task if none did.
+    LastSuccessful,
+}
+
+impl DuplicateTasks {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DuplicateTasks::All => "all",
+            DuplicateTasks::First => "first",
+            DuplicateTasks::Last => "last",
+            DuplicateTasks::LastSuccessful => "last-successful",
+        }
+    }
+
+    /// Whether `task` should replace `current` as the task for its label.
+    fn prefer(&self, task: &TaskGroupTask, current: &TaskGroupTask) -> bool {
+        let newer = task.task.created > current.task.created;
+        match self {
+            DuplicateTasks::All => false,
+            DuplicateTasks::First => task.task.created < current.task.created,
+            DuplicateTasks::Last => newer,
+            DuplicateTasks::LastSuccessful => {
+                let successful = |task: &TaskGroupTask| task.status.state == TaskState::Completed;
+                match (successful(task), successful(current)) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    _ => newer,
+                }
+            }
         }
-        is_match
     }
 }
 
-fn get_ci(repo: &str, taskcluster_base: Option<&str>) -> Option<Box<dyn TaskclusterCI>> {
-    match repo {
-        "wpt" => Some(Box::new(ghwpt::GithubCI::new(taskcluster_base))),
-        _ => {
-            if let Some(ci) = hgmo::HgmoCI::for_repo(taskcluster_base, repo.into()) {
-                Some(Box::new(ci))
+impl FromStr for DuplicateTasks {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<DuplicateTasks> {
+        match value {
+            "all" => Ok(DuplicateTasks::All),
+            "first" => Ok(DuplicateTasks::First),
+            "last" => Ok(DuplicateTasks::Last),
+            "last-successful" => Ok(DuplicateTasks::LastSuccessful),
+            _ => Err(Error::String(format!(
+                "Unknown duplicate task selection {}, expected all, first, last or last-successful",
+                value
+            ))),
+        }
+    }
+}
+
+/// Which of the tasks from a `TaskSource` to use.
+#[derive(Debug, Default)]
+pub struct TaskSelection {
+    /// Filters applied to task names; defaults to the filters for the repo.
+    pub task_filters: Option<Vec<TaskFilter>>,
+    /// For a commit, also include the tasks created by actions such as
+    /// retriggers, backfills and "Add new jobs".
+    pub include_actions: bool,
+    /// Which task to use when several have the same label.
+    pub duplicates: DuplicateTasks,
+}
+
+fn get_source_tasks(
+    client: &Client,
+    ci: &dyn TaskclusterCI,
+    source: &TaskSource,
+    include_actions: bool,
+) -> Result<Vec<TaskGroupTask>> {
+    let taskcluster = ci.taskcluster();
+    match source {
+        TaskSource::Commit(commit) => {
+            let taskgroups = ci.get_taskgroups(client, commit, include_actions)?;
+            let mut tasks = Vec::new();
+            for taskgroup in taskgroups {
+                tasks.extend(taskcluster.get_taskgroup_tasks(client, &taskgroup)?)
+            }
+            Ok(tasks)
+        }
+        TaskSource::TaskGroup(task_group_id) => {
+            taskcluster.get_taskgroup_tasks(client, task_group_id)
+        }
+        TaskSource::Task(task_id) => Ok(vec![taskcluster.get_task(client, task_id)?]),
+        TaskSource::Index(index_path) => {
+            let task_id = taskcluster.find_indexed_task(client, index_path)?.taskId;
+            let task = taskcluster.get_task(client, &task_id)?;
+            // A decision task's id is also the id of its task group
+            if task.status.taskGroupId == task_id {
+                taskcluster.get_taskgroup_tasks(client, &task_id)
             } else {
-                None
+                Ok(vec![task])
             }
         }
     }
 }
 
-pub fn check_complete(taskcluster_base: Option<&str>, repo: &str, commit: &str) -> Result<bool> {
-    let client = reqwest::blocking::Client::new();
-    let ci = get_ci(repo, taskcluster_base)
-        .ok_or_else(|| Error::String(format!("No such repo {}", repo)))?;
-    let taskgroups = ci.get_taskgroups(&client, commit)?;
-    let mut tasks = Vec::new();
-    for taskgroup in taskgroups {
-        tasks.extend(ci.taskcluster().get_taskgroup_tasks(&client, &taskgroup)?)
+/// The selected task filters, or the project's default filters. These don't
+/// apply to a single task, which is included unless filters are given.
+fn source_task_filters(
+    ci: &dyn TaskclusterCI,
+    source: &TaskSource,
+    selection: &TaskSelection,
+) -> Result<Vec<TaskFilter>> {
+    match (&selection.task_filters, source) {
+        (Some(task_filters), _) => Ok(task_filters.clone()),
+        (None, TaskSource::Task(_)) => Ok(Vec::new()),
+        (None, _) => ci.default_task_filter(),
+    }
+}
+
+/// Apply the task filters, then pick a single task for each label unless
+/// all duplicates are wanted.
+fn select_tasks(
+    tasks: Vec<TaskGroupTask>,
+    task_filters: &[TaskFilter],
+    duplicates: DuplicateTasks,
+) -> Vec<TaskGroupTask> {
+    let tasks = tasks
+        .into_iter()
+        .filter(|task| include_task(task, task_filters));
+    if duplicates == DuplicateTasks::All {
+        return tasks.collect();
+    }
+    let mut selected: Vec<TaskGroupTask> = Vec::new();
+    let mut by_label = BTreeMap::new();
+    for task in tasks {
+        match by_label.get(&task.task.metadata.name) {
+            Some(&idx) => {
+                if duplicates.prefer(&task, &selected[idx]) {
+                    selected[idx] = task;
+                }
+            }
+            None => {
+                by_label.insert(task.task.metadata.name.clone(), selected.len());
+                selected.push(task);
+            }
+        }
     }
+    selected
+}
+
+pub fn check_complete(
+    client: &Client,
+    taskcluster_base: Option<&str>,
+    project: &Project,
+    source: &TaskSource,
+) -> Result<bool> {
+    let ci = get_repo_ci(project, taskcluster_base);
+    let tasks = get_source_tasks(client, ci.as_ref(), source, false)?;
     Ok(tasks_complete(tasks.iter()))
 }
 
+fn get_selected_tasks(
+    client: &Client,
+    ci: &dyn TaskclusterCI,
+    source: &TaskSource,
+    selection: &TaskSelection,
+) -> Result<Vec<TaskGroupTask>> {
+    let task_filters = source_task_filters(ci, source, selection)?;
+    let tasks = get_source_tasks(client, ci, source, selection.include_actions)?;
+    Ok(select_tasks(tasks, &task_filters, selection.duplicates))
+}
+
+/// Get the tasks from `source` chosen by `selection`.
+pub fn get_tasks(
+    client: &Client,
+    taskcluster_base: Option<&str>,
+    project: &Project,
+    source: &TaskSource,
+    selection: &TaskSelection,
+) -> Result<Vec<TaskGroupTask>> {
+    let ci = get_repo_ci(project, taskcluster_base);
+    get_selected_tasks(client, ci.as_ref(), source, selection)
+}
+
+/// List the artifacts of the tasks from `source` chosen by `selection`. If
+/// `artifacts` is given, only artifacts matching one of the selectors are
+/// included.
+pub fn list_artifacts(
+    client: &Client,
+    taskcluster_base: Option<&str>,
+    project: &Project,
+    source: &TaskSource,
+    selection: &TaskSelection,
+    artifacts: Option<Vec<ArtifactSelector>>,
+) -> Result<Vec<(TaskGroupTask, Vec<Artifact>)>> {
+    let ci = get_repo_ci(project, taskcluster_base);
+    let tasks = get_selected_tasks(client, ci.as_ref(), source, selection)?;
+    let taskcluster = ci.taskcluster();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let results = runtime.block_on(async {
+        let async_client = AsyncClient::new(client, &Concurrency::default())?;
+        Ok::<_, Error>(
+            join_all(
+                tasks
+                    .iter()
+                    .map(|task| taskcluster.get_artifacts_async(&async_client, task, None)),
+            )
+            .await,
+        )
+    })?;
+    tasks
+        .into_iter()
+        .zip(results)
+        .map(|(task, task_artifacts)| {
+            let task_artifacts = task_artifacts?
+                .into_iter()
+                .filter(|artifact| {
+                    artifacts.as_ref().is_none_or(|selectors| {
+                        selectors
+                            .iter()
+                            .any(|selector| selector.is_match(&artifact.name))
+                    })
+                })
+                .collect();
+            Ok((task, task_artifacts))
+        })
+        .collect()
+}
+
+/// Options for waiting for tasks to complete before downloading their artifacts.
+#[derive(Debug, Clone)]
+pub struct WaitOptions {
+    /// Time between polls of the task groups.
+    pub interval: Duration,
+    /// Give up if the tasks still aren't complete after this long.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for WaitOptions {
+    fn default() -> Self {
+        WaitOptions {
+            interval: Duration::from_secs(60),
+            timeout: None,
+        }
+    }
+}
+
+/// Poll the tasks from `source` until they are all complete, calling `fetch`
+/// with the matching tasks that have completed since the previous poll, or
+/// that have completed again after being rerun. If the wait times out, the
+/// downloads so far are returned along with a `TimedOut` entry for each task
+/// that isn't complete.
+fn fetch_when_complete<F>(
+    client: &Client,
+    ci: &dyn TaskclusterCI,
+    source: &TaskSource,
+    selection: &TaskSelection,
+    wait: &WaitOptions,
+    mut fetch: F,
+) -> Result<Vec<TaskDownload>>
+where
+    F: FnMut(Vec<TaskGroupTask>) -> Result<Vec<TaskDownload>>,
+{
+    let task_filters = source_task_filters(ci, source, selection)?;
+    let start = Instant::now();
+    let mut found_tasks = false;
+    // The latest run of each task that has been fetched
+    let mut fetched = BTreeMap::new();
+    let mut downloads: Vec<TaskDownload> = Vec::new();
+    loop {
+        let poll_start = Instant::now();
+        let all_tasks = match get_source_tasks(client, ci, source, selection.include_actions) {
+            Ok(tasks) => {
+                found_tasks = true;
+                tasks
+            }
+            // The decision task may not have been created yet
+            Err(err) if !found_tasks => {
+                warn!("Tasks for {} not yet available: {}", source, err);
+                Vec::new()
+            }
+            Err(err) => return Err(err),
+        };
+        let done = !all_tasks.is_empty() && tasks_complete(all_tasks.iter());
+        let tasks = select_tasks(all_tasks, &task_filters, selection.duplicates);
+        let complete_count = tasks
+            .iter()
+            .filter(|task| task.status.state.is_complete())
+            .count();
+        if found_tasks {
+            info!("{} of {} tasks complete", complete_count, tasks.len());
+        }
+
+        let (ready, pending): (Vec<_>, Vec<_>) = tasks
+            .into_iter()
+            .filter(|task| {
+                fetched.get(&task.status.taskId)
+                    != Some(&task.status.runs.last().map(|run| run.runId))
+            })
+            .partition(|task| task.status.state.is_complete());
+        if !ready.is_empty() {
+            for task in ready.iter() {
+                let task_id = &task.status.taskId;
+                let run_id = task.status.runs.last().map(|run| run.runId);
+                if fetched.insert(task_id.clone(), run_id).is_some() {
+                    info!("Task {} was rerun, fetching its artifacts again", task_id);
+                    remove_task_downloads(&mut downloads, task_id);
+                }
+            }
+            downloads.extend(fetch(ready)?);
+        }
+
+        if done {
+            if fetched.is_empty() {
+                return Err(Error::String("No matching tasks found".into()));
+            }
+            return Ok(downloads);
+        }
+        if let Some(timeout) = wait.timeout {
+            if start.elapsed() >= timeout {
+                if fetched.is_empty() && pending.is_empty() {
+                    return Err(Error::String(format!(
+                        "No matching tasks found after waiting {}s",
+                        timeout.as_secs()
+                    )));
+                }
+                warn!(
+                    "{} tasks still not complete after waiting {}s",
+                    pending.len(),
+                    timeout.as_secs()
+                );
+                downloads.extend(pending.into_iter().map(|task| {
+                    let status = DownloadStatus::TimedOut(Error::String(format!(
+                        "Task was still {} after waiting {}s",
+                        task.status.state.as_str(),
+                        timeout.as_secs()
+                    )));
+                    TaskDownload {
+                        task,
+                        run_id: None,
+                        artifact: None,
+                        status,
+                        sha256: None,
+                    }
+                }));
+                return Ok(downloads);
+            }
+        }
+        sleep(wait.interval.saturating_sub(poll_start.elapsed()));
+    }
+}
+
+/// Remove the earlier downloads from a task that's being fetched again after
+/// a rerun, deleting their files, including existing files that were
+/// skipped, so they're replaced by the artifacts of the new run.
+fn remove_task_downloads(downloads: &mut Vec<TaskDownload>, task_id: &str) {
+    downloads.retain(|download| {
+        if download.task.status.taskId != task_id {
+            return true;
+        }
+        if let Some(path) = download.status.path() {
+            if let Err(err) = fs::remove_file(path) {
+                warn!("Removing {} failed: {}", path.display(), err);
+            }
+        }
+        false
+    });
+}
+
+/// Options controlling which artifacts `download_artifacts` fetches and how.
+#[derive(Debug, Default)]
+pub struct DownloadOptions {
+    /// The tasks from which to download artifacts.
+    pub selection: TaskSelection,
+    /// Artifacts to download; defaults to the default artifact for the project.
+    pub artifacts: Option<Vec<ArtifactSelector>>,
+    /// Fail if any matching task is not yet complete.
+    pub check_complete: bool,
+    /// Runs of each task to fetch artifacts from; defaults to the latest
+    /// run. When given, the run id is included in the file names.
+    pub runs: Option<RunSelection>,
+    /// Template for the paths of the downloaded files relative to the output
+    /// directory; defaults to putting them all directly in the output
+    /// directory.
+    pub output_template: Option<OutputTemplate>,
+    /// Encoding of the downloaded files, e.g. to compress them.
+    pub encoding: OutputEncoding,
+    /// Path at which to write a JSON manifest describing the fetched artifacts.
+    pub manifest: Option<PathBuf>,
+    /// Wait for the tasks to complete, downloading artifacts from each
+    /// task as soon as it finishes.
+    pub wait: Option<WaitOptions>,
+    /// Cache in which to look for artifacts before downloading them, and to
+    /// which downloaded artifacts are added.
+    pub cache: Option<ArtifactCache>,
+    /// Limits on the number of concurrent requests.
+    pub concurrency: Concurrency,
+    /// Observer notified as tasks are listed and artifacts downloaded.
+    pub progress: Option<Arc<dyn ProgressObserver>>,
+}
+
+/// Download the selected artifacts of the tasks from `source` into
+/// `out_dir`. Artifacts are fetched concurrently on an internal async
+/// runtime, so this must not be called from within an async context.
 pub fn download_artifacts(
+    client: &Client,
     taskcluster_base: Option<&str>,
-    repo: &str,
-    commit: &str,
-    task_filters: Option<Vec<TaskFilter>>,
-    artifact_name: Option<&str>,
-    check_complete: bool,
+    project: &Project,
+    source: &TaskSource,
     out_dir: &Path,
-    compress: bool,
-) -> Result<Vec<(TaskGroupTask, PathBuf)>> {
-    let client = reqwest::blocking::Client::new();
+    options: DownloadOptions,
+) -> Result<Vec<TaskDownload>> {
+    let ci = get_repo_ci(project, taskcluster_base);
+
+    let artifact_selectors = match options.artifacts {
+        Some(selectors) => selectors,
+        None => vec![ArtifactSelector::new(ci.default_artifact_name())?],
+    };
+
+    let template = options
+        .output_template
+        .unwrap_or_else(|| OutputTemplate::default_for(options.runs.is_some()));
+    let commit = match source {
+        TaskSource::Commit(commit) => Some(commit.as_str()),
+        _ => None,
+    };
+    if template.uses_commit() && commit.is_none() {
+        return Err(Error::String(
+            "The {commit} placeholder can only be used when fetching the tasks for a commit".into(),
+        ));
+    }
+    let repo = project.name();
 
-    let ci = get_ci(repo, taskcluster_base)
-        .ok_or_else(|| Error::String(format!("No such repo {}", repo)))?;
+    let fetcher = ArtifactFetcher {
+        client,
+        taskcluster: ci.taskcluster(),
+        out_dir,
+        artifact_selectors: &artifact_selectors,
+        runs: options.runs,
+        template: &template,
+        repo: &repo,
+        commit,
+        encoding: &options.encoding,
+        cache: options.cache.as_ref(),
+        concurrency: &options.concurrency,
+        progress: options.progress.as_deref().unwrap_or(&()),
+        paths: PathClaims::default(),
+    };
+    let fetch = |tasks| fetcher.fetch(tasks);
+
+    let downloads = if let Some(ref wait) = options.wait {
+        fetch_when_complete(client, ci.as_ref(), source, &options.selection, wait, fetch)?
+    } else {
+        let tasks = get_selected_tasks(client, ci.as_ref(), source, &options.selection)?;
+
+        if options.check_complete && !tasks_complete(tasks.iter()) {
+            return Err(Error::String("Tasks are not yet complete".into()));
+        }
+
+        if tasks.is_empty() {
+            return Err(Error::String("No matching tasks found".into()));
+        }
 
-    let task_filters = task_filters.unwrap_or_else(|| ci.default_task_filter());
-    let taskgroups = ci.get_taskgroups(&client, commit)?;
-    let artifact_name = artifact_name.unwrap_or_else(|| ci.default_artifact_name());
+        fetch(tasks)?
+    };
 
-    let mut tasks = Vec::new();
-    for taskgroup in taskgroups {
-        tasks.extend(ci.taskcluster().get_taskgroup_tasks(&client, &taskgroup)?)
+    if let Some(ref cache) = options.cache {
+        if let Err(err) = cache.gc() {
+            warn!("Removing old artifacts from the cache failed: {}", err);
+        }
     }
-    let tasks: Vec<TaskGroupTask> = tasks
-        .into_iter()
-        .filter(|task| include_task(task, &task_filters))
-        .collect();
 
-    if check_complete && !tasks_complete(tasks.iter()) {
-        return Err(Error::String("wpt tasks are not yet complete".into()));
+    if let Some(manifest_path) = options.manifest {
+        Manifest::new(&repo, source, out_dir, &downloads, &options.encoding)?
+            .write(&manifest_path)?;
+    }
+
+    Ok(downloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{artifact, task};
+
+    #[test]
+    fn remove_rerun_downloads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let download = |task_id: &str, name: &str, status: fn(PathBuf) -> DownloadStatus| {
+            let path = tmp.path().join(format!("{}-{}", task_id, name));
+            fs::write(&path, name).unwrap();
+            let mut task = task("test-linux");
+            task.status.taskId = task_id.into();
+            TaskDownload {
+                task,
+                run_id: Some(0),
+                artifact: Some(artifact(name)),
+                status: status(path),
+                sha256: None,
+            }
+        };
+        let mut downloads = vec![
+            download("T1", "downloaded", DownloadStatus::Downloaded),
+            download("T1", "cached", DownloadStatus::Cached),
+            download("T1", "existing", DownloadStatus::SkippedExisting),
+            download("T2", "downloaded", DownloadStatus::Downloaded),
+            download("T2", "existing", DownloadStatus::SkippedExisting),
+        ];
+        remove_task_downloads(&mut downloads, "T1");
+        assert_eq!(downloads.len(), 2);
+        assert!(downloads
+            .iter()
+            .all(|download| download.task.status.taskId == "T2"));
+        for name in ["downloaded", "cached", "existing"] {
+            assert!(!tmp.path().join(format!("T1-{}", name)).exists());
+        }
+        for name in ["downloaded", "existing"] {
+            assert!(tmp.path().join(format!("T2-{}", name)).exists());
+        }
     }
 
-    if tasks.is_empty() {
-        return Err(Error::String("No matching tasks found".into()));
+    fn selects(selector: &str, name: &str) -> bool {
+        ArtifactSelector::new(selector).unwrap().is_match(name)
     }
 
-    Ok(fetch_job_logs(
-        &client,
-        ci.taskcluster(),
-        out_dir,
-        tasks,
-        artifact_name,
-        compress,
-    ))
+    #[test]
+    fn selector_escaping() {
+        assert!(selects("report.json", "public/report.json"));
+        assert!(!selects("report.json", "public/report-json"));
+        assert!(selects("a+b(1).log", "public/a+b(1).log"));
+        assert!(!selects("a+b(1).log", "public/aab1.log"));
+        assert!(selects("$HOME^", "$HOME^"));
+    }
+
+    #[test]
+    fn selector_wildcards() {
+        assert!(selects("*.json", "public/test_info/report.json"));
+        assert!(!selects("test_info*.json", "public/test_info/report.json"));
+        assert!(selects(
+            "public/*/report.json",
+            "public/test_info/report.json"
+        ));
+        assert!(!selects("public/*/report.json", "public/a/b/report.json"));
+        assert!(selects("public/**/report.json", "public/a/b/report.json"));
+        assert!(selects("/public/**.json", "public/a/b/report.json"));
+        assert!(selects("log?.txt", "public/log1.txt"));
+        assert!(!selects("log?.txt", "public/log12.txt"));
+        assert!(!selects("a?b", "a/b"));
+        assert!(selects("log[0-9].txt", "public/log3.txt"));
+        assert!(!selects("log[!0-9].txt", "public/log3.txt"));
+        assert!(selects("log[!0-9].txt", "public/logx.txt"));
+        assert!(ArtifactSelector::new("log[0-9.txt").is_err());
+    }
+
+    #[test]
+    fn selector_anchoring() {
+        // Unanchored selectors match whole trailing path segments
+        assert!(selects("wptreport.json", "public/test_info/wptreport.json"));
+        assert!(selects("wptreport.json", "wptreport.json"));
+        assert!(!selects(
+            "wptreport.json",
+            "public/test_info/mywptreport.json"
+        ));
+        assert!(!selects("wptreport", "public/test_info/wptreport.json"));
+        assert!(selects(
+            "test_info/wptreport.json",
+            "public/test_info/wptreport.json"
+        ));
+        // A leading / matches the full name
+        assert!(selects(
+            "/public/test_info/wptreport.json",
+            "public/test_info/wptreport.json"
+        ));
+        assert!(!selects(
+            "/test_info/wptreport.json",
+            "public/test_info/wptreport.json"
+        ));
+    }
 }
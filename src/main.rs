@@ -1,36 +1,32 @@
-use clap::{Arg, ArgAction, Command};
-use log::error;
+use clap::{value_parser, Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{error, info};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::path::PathBuf;
-use tcfetch::{download_artifacts, Error, Result, TaskFilter};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tcfetch::config::CacheConfig;
+use tcfetch::encoding::OutputEncoding;
+use tcfetch::manifest::Manifest;
+use tcfetch::progress::ProgressObserver;
+use tcfetch::taskcluster::{tasks_complete, Artifact, Credentials, TaskGroupTask};
+use tcfetch::template::OutputTemplate;
+use tcfetch::verify::{verify_manifest, VerifyStatus};
+use tcfetch::{
+    download_artifacts, get_tasks, list_artifacts, ArtifactSelector, Client, Concurrency, Config,
+    DownloadOptions, DownloadStatus, Error, Project, Result, RetryPolicy, TaskFilter,
+    TaskSelection, TaskSource, WaitOptions,
+};
 
-fn parse_args() -> Command {
-    Command::new("Taskcluster artifact fetcher")
-        .arg(
-            Arg::new("check_complete")
-                .long("check-complete")
-                .required(false)
-                .action(ArgAction::SetTrue)
-                .help("Check if there are any pending wpt jobs and exit with code 1 if there are"),
-        )
-        .arg(
-            Arg::new("compress")
-                .long("compress")
-                .required(false)
-                .action(ArgAction::SetTrue)
-                .help("Compress output as zstd"),
-        )
-        .arg(
-            Arg::new("out_dir")
-                .long("out-dir")
-                .required(false)
-                .help("Directory in which to put output files"),
-        )
-        .arg(
-            Arg::new("artifact_name")
-                .long("artifact-name")
-                .help("Artifact name to fetch (defaults to wptreport artifact)"),
-        )
+/// Add the arguments used to select the tasks for a commit.
+fn task_args(command: Command) -> Command {
+    command
         .arg(
             Arg::new("taskcluster_url")
                 .long("taskcluster-url")
@@ -42,38 +38,686 @@ fn parse_args() -> Command {
                 .action(ArgAction::Append)
                 .help("Regex to filter task names. If this starts with ! then a matching task is excluded. If it start with ^ (after removing any !) the remaining regex is applied to the start of the task string, otherwise any prefix is allowed. Tasks must match all given filters."),
         )
+        .arg(
+            Arg::new("filter")
+                .long("filter")
+                .action(ArgAction::Append)
+                .help("Condition on tasks: state=<state>[,<state>...], worker-type=<type>, provisioner-id=<id>, tag:<name>[=<value>], extra:<JSON pointer>[=<value>] or name=<regex>. If this starts with ! then a matching task is excluded. Tasks must match all given filters."),
+        )
+        .arg(
+            Arg::new("filter_expr")
+                .long("filter-expr")
+                .action(ArgAction::Append)
+                .help("Boolean expression selecting tasks, e.g. \"(linux or mac) and not asan and state=completed\". Combines conditions as accepted by --filter, or bare regexes matched against the task name, with and, or, not and parentheses. Tasks must match all given filters."),
+        )
+        .arg(
+            Arg::new("check_name")
+                .long("check-name")
+                .help("Name of the decision task check run, for GitHub repos"),
+        )
         .arg(
             Arg::new("repo")
                 .required(true)
                 .index(1)
-                .help("Repo in which jobs ran"),
+                .help("Repo in which jobs ran: the name of a built-in or configured repo, or owner/repo for a GitHub repo using the Taskcluster GitHub integration"),
         )
         .arg(
             Arg::new("commit")
-                .required(true)
                 .index(2)
                 .help("Commit hash"),
         )
+        .arg(
+            Arg::new("task_group")
+                .long("task-group")
+                .help("Use the tasks in this task group instead of those for a commit"),
+        )
+        .arg(
+            Arg::new("task")
+                .long("task")
+                .help("Use this single task instead of the tasks for a commit"),
+        )
+        .arg(
+            Arg::new("index")
+                .long("index")
+                .help("Use the task at this index path instead of the tasks for a commit. If it's a decision task, all the tasks in its task group are used"),
+        )
+        .arg(
+            Arg::new("include_actions")
+                .long("include-actions")
+                .action(ArgAction::SetTrue)
+                .help("Also include tasks created by actions such as retriggers, backfills and \"Add new jobs\" for the commit (hg.mozilla.org repos only)"),
+        )
+        .arg(
+            Arg::new("duplicates")
+                .long("duplicates")
+                .value_parser(["all", "first", "last", "last-successful"])
+                .help("Which task to use when several have the same label, e.g. after retriggers. last-successful picks the most recent task that completed successfully, if any (default: all)"),
+        )
+        .group(
+            ArgGroup::new("source")
+                .args(["commit", "task_group", "task", "index"])
+                .required(true),
+        )
 }
 
-fn run() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+fn artifact_name_arg(help: &'static str) -> Arg {
+    Arg::new("artifact_name")
+        .long("artifact-name")
+        .action(ArgAction::Append)
+        .help(help)
+}
 
-    let matches = parse_args().get_matches();
-    let repo = matches.get_one::<String>("repo").unwrap();
-    let commit = matches.get_one::<String>("commit").unwrap();
+fn parse_args() -> Command {
+    Command::new("Taskcluster artifact fetcher")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Write output to stdout as JSON"),
+        )
+        .arg(
+            Arg::new("credentials")
+                .long("credentials")
+                .global(true)
+                .help("JSON file containing Taskcluster clientId, accessToken and optional certificate. Defaults to the TASKCLUSTER_CLIENT_ID, TASKCLUSTER_ACCESS_TOKEN and TASKCLUSTER_CERTIFICATE environment variables"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .global(true)
+                .help("TOML file defining additional repos. Defaults to $XDG_CONFIG_HOME/tcfetch/config.toml"),
+        )
+        .arg(
+            Arg::new("github_token")
+                .long("github-token")
+                .global(true)
+                .help("Token for authenticating GitHub API requests. Defaults to the GITHUB_TOKEN environment variable"),
+        )
+        .arg(
+            Arg::new("wait_for_rate_limit")
+                .long("wait-for-rate-limit")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("When the GitHub API rate limit is exceeded, wait for it to reset instead of failing"),
+        )
+        .arg(
+            Arg::new("cache_dir")
+                .long("cache-dir")
+                .global(true)
+                .help("Directory in which to cache downloaded artifacts and API responses. Defaults to $XDG_CACHE_HOME/tcfetch"),
+        )
+        .arg(
+            Arg::new("cache_max_size")
+                .long("cache-max-size")
+                .global(true)
+                .help("Maximum size of the artifact cache, e.g. 500M or 20G, or none for no limit (default: 10G). The least recently used artifacts are removed when it's exceeded"),
+        )
+        .arg(
+            Arg::new("cache_link")
+                .long("cache-link")
+                .global(true)
+                .value_parser(["auto", "reflink", "hardlink", "copy"])
+                .help("How to place cached artifacts in the output directory (default: auto, which uses a reflink if possible, then a hardlink, then a copy)"),
+        )
+        .arg(
+            Arg::new("no_cache")
+                .long("no-cache")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Don't use the artifact or API response caches"),
+        )
+        .arg(
+            Arg::new("refresh")
+                .long("refresh")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .conflicts_with("no_cache")
+                .help("Don't reuse cached API responses, but fetch them again and update the cache"),
+        )
+        .arg(
+            Arg::new("max_attempts")
+                .long("max-attempts")
+                .global(true)
+                .value_parser(value_parser!(u32).range(1..))
+                .help("Maximum number of attempts for each HTTP request (default: 5)"),
+        )
+        .arg(
+            Arg::new("retry_backoff")
+                .long("retry-backoff")
+                .global(true)
+                .value_parser(value_parser!(f64))
+                .help("Delay in seconds before the first retry, doubled for each further retry (default: 1)"),
+        )
+        .arg(
+            Arg::new("max_retry_backoff")
+                .long("max-retry-backoff")
+                .global(true)
+                .value_parser(value_parser!(f64))
+                .help("Maximum delay in seconds between retries (default: 60)"),
+        )
+        .arg(
+            Arg::new("no_retry_jitter")
+                .long("no-retry-jitter")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Don't randomise the delay between retries"),
+        )
+        .arg(
+            Arg::new("retry_status")
+                .long("retry-status")
+                .global(true)
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(u16))
+                .help("HTTP status code to retry. May be repeated; replaces the default of 408, 429, 500, 502, 503 and 504"),
+        )
+        .arg(
+            Arg::new("ignore_retry_after")
+                .long("ignore-retry-after")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Ignore Retry-After headers when computing the delay between retries"),
+        )
+        .subcommand(task_args(
+            Command::new("fetch")
+                .about("Download artifacts from the tasks for a commit")
+                .arg(
+                    Arg::new("check_complete")
+                        .long("check-complete")
+                        .required(false)
+                        .action(ArgAction::SetTrue)
+                        .help("Fail without downloading anything if there are any pending tasks"),
+                )
+                .arg(
+                    Arg::new("wait")
+                        .long("wait")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("check_complete")
+                        .help("Wait for the tasks to complete, downloading artifacts from each task as soon as it finishes"),
+                )
+                .arg(
+                    Arg::new("wait_interval")
+                        .long("wait-interval")
+                        .requires("wait")
+                        .value_parser(value_parser!(f64))
+                        .help("Time in seconds between checks for completed tasks (default: 60)"),
+                )
+                .arg(
+                    Arg::new("wait_timeout")
+                        .long("wait-timeout")
+                        .requires("wait")
+                        .value_parser(value_parser!(f64))
+                        .help("Give up if the tasks aren't complete after this many seconds"),
+                )
+                .arg(
+                    Arg::new("runs")
+                        .long("runs")
+                        .value_parser(["all", "first", "last", "successful", "failed"])
+                        .help("Fetch artifacts from these runs of each task, rather than the latest run. Successful runs are those that completed, and failed runs those that failed or ended in an exception. The run id is added to the file names"),
+                )
+                .arg(
+                    Arg::new("api_concurrency")
+                        .long("api-concurrency")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .help("Maximum number of concurrent Taskcluster API requests (default: 8)"),
+                )
+                .arg(
+                    Arg::new("download_concurrency")
+                        .long("download-concurrency")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .help("Maximum number of concurrent artifact downloads (default: 8)"),
+                )
+                .arg(
+                    Arg::new("host_concurrency")
+                        .long("host-concurrency")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .help("Maximum number of concurrent requests to any one host (default: 8)"),
+                )
+                .arg(
+                    Arg::new("no_progress")
+                        .long("no-progress")
+                        .action(ArgAction::SetTrue)
+                        .help("Don't display a progress bar"),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .long("compress")
+                        .required(false)
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("output_encoding")
+                        .help("Compress output as zstd; the same as --output-encoding zstd"),
+                )
+                .arg(
+                    Arg::new("output_encoding")
+                        .long("output-encoding")
+                        .value_parser(["raw", "decompressed", "gzip", "zstd"])
+                        .help("Encoding of output files: as uploaded (raw, the default), decompressed, or compressed with gzip or zstd. Artifacts that are already gzip or zstd compressed are detected and transcoded if necessary"),
+                )
+                .arg(
+                    Arg::new("compression_level")
+                        .long("compression-level")
+                        .value_parser(value_parser!(i32))
+                        .allow_negative_numbers(true)
+                        .help("Compression level for gzip (0-9) or zstd (1-22) output"),
+                )
+                .arg(
+                    Arg::new("zstd_long")
+                        .long("zstd-long")
+                        .value_name("WINDOW_LOG")
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("27")
+                        .value_parser(value_parser!(u32))
+                        .help("Use zstd long distance matching, with a window of 2^WINDOW_LOG bytes (default: 27)"),
+                )
+                .arg(
+                    Arg::new("zstd_dictionary")
+                        .long("zstd-dictionary")
+                        .value_name("PATH")
+                        .help("Dictionary to use for zstd compression"),
+                )
+                .arg(
+                    Arg::new("out_dir")
+                        .long("out-dir")
+                        .required(false)
+                        .help("Directory in which to put output files"),
+                )
+                .arg(
+                    Arg::new("output_template")
+                        .long("output-template")
+                        .value_name("TEMPLATE")
+                        .help("Template for the path of each file relative to the output directory, e.g. '{repo}/{platform}/{label}/{run_id}/{artifact_basename}'. See the README for the placeholders"),
+                )
+                .arg(
+                    Arg::new("manifest")
+                        .long("manifest")
+                        .help("Path at which to write a JSON manifest describing the downloaded artifacts"),
+                )
+                .arg(artifact_name_arg("Artifact name to fetch (defaults to the wptreport artifact for wpt and Gecko repos, and the task log for other GitHub repos). If this starts with / it must match the full artifact path, otherwise it matches trailing path segments. Supports the glob wildcards *, ** and ?. May be repeated to fetch several artifacts from each task.")),
+        ))
+        .subcommand(task_args(
+            Command::new("status")
+                .about("Print the number of tasks in each state, and exit with code 3 if any are pending"),
+        ))
+        .subcommand(task_args(
+            Command::new("list-tasks").about("List the name, id and state of each task"),
+        ))
+        .subcommand(task_args(
+            Command::new("list-artifacts")
+                .about("List the artifacts of each task")
+                .arg(artifact_name_arg("Only list artifacts matching this name, using the same syntax as for fetch. May be repeated.")),
+        ))
+        .subcommand(
+            Command::new("verify")
+                .about("Check that the files listed in a manifest written by fetch are intact")
+                .arg(
+                    Arg::new("manifest")
+                        .required(true)
+                        .help("Path to the manifest"),
+                )
+                .arg(
+                    Arg::new("taskcluster_url")
+                        .long("taskcluster-url")
+                        .help("Base url of the taskcluster instance"),
+                )
+                .arg(
+                    Arg::new("out_dir")
+                        .long("out-dir")
+                        .help("Directory containing the fetched files, if it's been moved away from the manifest since the fetch"),
+                )
+                .arg(
+                    Arg::new("check_taskcluster")
+                        .long("check-taskcluster")
+                        .action(ArgAction::SetTrue)
+                        .help("Also compare each file with the SHA-256 that Taskcluster publishes for the artifact, in the task's chain of trust or from the object service"),
+                )
+                .arg(
+                    Arg::new("remove_invalid")
+                        .long("remove-invalid")
+                        .action(ArgAction::SetTrue)
+                        .help("Delete files that fail verification, so that the next fetch downloads them again"),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Manage the artifact and API response caches")
+                .subcommand_required(true)
+                .subcommand(Command::new("stats").about("Print the number and size of cached artifacts and API responses"))
+                .subcommand(Command::new("gc").about("Remove the least recently used artifacts until the cache is within its maximum size, and API responses that haven't been used for 30 days")),
+        )
+}
+
+fn duration_arg(matches: &ArgMatches, name: &str) -> Result<Option<Duration>> {
+    matches
+        .get_one::<f64>(name)
+        .map(|secs| {
+            Duration::try_from_secs_f64(*secs)
+                .map_err(|_| Error::String(format!("Invalid duration {} for {}", secs, name)))
+        })
+        .transpose()
+}
+
+fn retry_policy(matches: &ArgMatches) -> Result<RetryPolicy> {
+    let mut retry = RetryPolicy::default();
+    if let Some(max_attempts) = matches.get_one::<u32>("max_attempts") {
+        retry.max_attempts = *max_attempts;
+    }
+    if let Some(backoff) = duration_arg(matches, "retry_backoff")? {
+        retry.initial_backoff = backoff;
+    }
+    if let Some(backoff) = duration_arg(matches, "max_retry_backoff")? {
+        retry.max_backoff = backoff;
+    }
+    if let Some(statuses) = matches.get_many::<u16>("retry_status") {
+        retry.retry_statuses = statuses.copied().collect();
+    }
+    retry.jitter = !matches.get_flag("no_retry_jitter");
+    retry.honor_retry_after = !matches.get_flag("ignore_retry_after");
+    Ok(retry)
+}
+
+fn client(matches: &ArgMatches, config: &Config) -> Result<Client> {
+    let mut client = Client::new(retry_policy(matches)?)
+        .with_wait_for_github_rate_limit(matches.get_flag("wait_for_rate_limit"));
+    if let Some(cache) = cache_config(matches, config)?.response_cache() {
+        client = client.with_response_cache(cache.with_refresh(matches.get_flag("refresh")));
+    }
+    let github_token = matches
+        .get_one::<String>("github_token")
+        .cloned()
+        .or_else(|| env::var("GITHUB_TOKEN").ok());
+    if let Some(token) = github_token.filter(|token| !token.is_empty()) {
+        client = client.with_github_token(token);
+    }
+    let credentials = match matches.get_one::<String>("credentials") {
+        Some(path) => Some(Credentials::from_file(Path::new(path))?),
+        None => Credentials::from_env(),
+    };
+    Ok(match credentials {
+        Some(credentials) => client.with_taskcluster_credentials(credentials),
+        None => client,
+    })
+}
+
+fn cache_config(matches: &ArgMatches, config: &Config) -> Result<CacheConfig> {
+    let mut cache_config = config.cache.clone();
+    cache_config.merge(CacheConfig {
+        enabled: matches.get_flag("no_cache").then_some(false),
+        dir: matches.get_one::<String>("cache_dir").map(PathBuf::from),
+        max_size: matches.get_one::<String>("cache_max_size").cloned(),
+        link: matches
+            .get_one::<String>("cache_link")
+            .map(|value| value.parse())
+            .transpose()?,
+    });
+    Ok(cache_config)
+}
+
+fn project(matches: &ArgMatches, config: &Config) -> Result<Project> {
+    let mut project = config.project(matches.get_one::<String>("repo").unwrap())?;
+    if let Some(check_name) = matches.get_one::<String>("check_name") {
+        match project {
+            Project::Github(ref mut project) => project.check_name = check_name.clone(),
+            Project::Hgmo(_) => {
+                return Err(Error::String(
+                    "--check-name is only supported for GitHub repos".into(),
+                ))
+            }
+        }
+    }
+    Ok(project)
+}
+
+fn task_source(matches: &ArgMatches) -> TaskSource {
+    let arg = |name| matches.get_one::<String>(name).cloned();
+    if let Some(task_group_id) = arg("task_group") {
+        TaskSource::TaskGroup(task_group_id)
+    } else if let Some(task_id) = arg("task") {
+        TaskSource::Task(task_id)
+    } else if let Some(index_path) = arg("index") {
+        TaskSource::Index(index_path)
+    } else {
+        TaskSource::Commit(arg("commit").expect("Task source is required"))
+    }
+}
+
+fn wait_options(matches: &ArgMatches) -> Result<Option<WaitOptions>> {
+    if !matches.get_flag("wait") {
+        return Ok(None);
+    }
+    let mut wait = WaitOptions::default();
+    if let Some(interval) = duration_arg(matches, "wait_interval")? {
+        wait.interval = interval;
+    }
+    wait.timeout = duration_arg(matches, "wait_timeout")?;
+    Ok(Some(wait))
+}
+
+fn concurrency(matches: &ArgMatches) -> Concurrency {
+    let mut concurrency = Concurrency::default();
+    let arg = |name| matches.get_one::<u32>(name).map(|value| *value as usize);
+    if let Some(api) = arg("api_concurrency") {
+        concurrency.api = api;
+    }
+    if let Some(downloads) = arg("download_concurrency") {
+        concurrency.downloads = downloads;
+    }
+    if let Some(per_host) = arg("host_concurrency") {
+        concurrency.per_host = per_host;
+    }
+    concurrency
+}
+
+fn output_template(matches: &ArgMatches, config: &Config) -> Result<Option<OutputTemplate>> {
+    match matches.get_one::<String>("output_template") {
+        Some(template) => template.parse().map(Some),
+        None => config
+            .output_template
+            .as_ref()
+            .map(|template| template.parse())
+            .transpose(),
+    }
+}
+
+fn output_encoding(matches: &ArgMatches) -> Result<OutputEncoding> {
+    let name = if matches.get_flag("compress") {
+        "zstd"
+    } else {
+        matches
+            .get_one::<String>("output_encoding")
+            .map(|name| name.as_str())
+            .unwrap_or("raw")
+    };
+    OutputEncoding::new(
+        name,
+        matches.get_one::<i32>("compression_level").copied(),
+        matches.get_one::<u32>("zstd_long").copied(),
+        matches
+            .get_one::<String>("zstd_dictionary")
+            .map(PathBuf::from),
+    )
+}
+
+fn task_selection(matches: &ArgMatches) -> Result<TaskSelection> {
+    let mut task_filters = Vec::new();
+    for filter in matches
+        .get_many::<String>("filter_re")
+        .into_iter()
+        .flatten()
+    {
+        task_filters.push(TaskFilter::new(filter)?);
+    }
+    for filter in matches.get_many::<String>("filter").into_iter().flatten() {
+        task_filters.push(TaskFilter::parse(filter)?);
+    }
+    for expression in matches
+        .get_many::<String>("filter_expr")
+        .into_iter()
+        .flatten()
+    {
+        task_filters.push(TaskFilter::expression(expression)?);
+    }
+    let task_filters = (!task_filters.is_empty()).then_some(task_filters);
+    Ok(TaskSelection {
+        task_filters,
+        include_actions: matches.get_flag("include_actions"),
+        duplicates: matches
+            .get_one::<String>("duplicates")
+            .map(|value| value.parse())
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+fn artifact_selectors(matches: &ArgMatches) -> Result<Option<Vec<ArtifactSelector>>> {
+    matches
+        .get_many::<String>("artifact_name")
+        .map(|names| {
+            names
+                .map(|name| ArtifactSelector::new(name))
+                .collect::<Result<Vec<ArtifactSelector>>>()
+        })
+        .transpose()
+}
+
+fn write_json(value: &serde_json::Value) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// Progress bar showing the bytes downloaded so far, and the number of tasks
+/// and artifacts fetched.
+struct FetchProgress {
+    bar: ProgressBar,
+    state: Mutex<FetchProgressState>,
+}
+
+#[derive(Default)]
+struct FetchProgressState {
+    tasks: usize,
+    tasks_done: usize,
+    artifacts: usize,
+    artifacts_done: usize,
+    /// Bytes received and expected size of each download that has started.
+    downloads: HashMap<PathBuf, (u64, Option<u64>)>,
+    downloaded: u64,
+    /// Expected size of all started downloads, counting the bytes received
+    /// so far for those without a known size.
+    total: u64,
+}
+
+impl FetchProgress {
+    fn new(multi: &MultiProgress) -> FetchProgress {
+        let style = ProgressStyle::with_template(
+            "{spinner} [{elapsed_precise}] [{wide_bar}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {eta}) {msg}",
+        )
+        .expect("Invalid progress bar template")
+        .progress_chars("=> ");
+        let bar = multi.add(ProgressBar::new(0).with_style(style));
+        bar.enable_steady_tick(Duration::from_millis(200));
+        FetchProgress {
+            bar,
+            state: Mutex::new(FetchProgressState::default()),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut FetchProgressState)) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        self.bar.set_length(state.total);
+        self.bar.set_position(state.downloaded);
+        self.bar.set_message(format!(
+            "{}/{} tasks, {}/{} artifacts",
+            state.tasks_done, state.tasks, state.artifacts_done, state.artifacts
+        ));
+    }
+}
+
+impl ProgressObserver for FetchProgress {
+    fn tasks_found(&self, count: usize) {
+        self.update(|state| state.tasks += count)
+    }
+
+    fn artifacts_found(&self, _task: &TaskGroupTask, count: usize) {
+        self.update(|state| state.artifacts += count)
+    }
+
+    fn download_started(&self, dest: &Path, downloaded: u64, total: Option<u64>) {
+        self.update(|state| {
+            if let Some((prev_downloaded, prev_total)) = state
+                .downloads
+                .insert(dest.to_path_buf(), (downloaded, total))
+            {
+                state.downloaded -= prev_downloaded;
+                state.total -= prev_total.unwrap_or(prev_downloaded);
+            }
+            state.downloaded += downloaded;
+            state.total += total.unwrap_or(downloaded);
+        })
+    }
+
+    fn bytes_downloaded(&self, dest: &Path, bytes: u64) {
+        self.update(|state| {
+            if let Some((downloaded, total)) = state.downloads.get_mut(dest) {
+                *downloaded += bytes;
+                state.downloaded += bytes;
+                if total.is_none() {
+                    state.total += bytes;
+                }
+            }
+        })
+    }
+
+    fn artifact_finished(
+        &self,
+        _task: &TaskGroupTask,
+        _artifact: &Artifact,
+        status: &DownloadStatus,
+    ) {
+        self.update(|state| {
+            state.artifacts_done += 1;
+            // A failed download won't receive the rest of its bytes.
+            let DownloadStatus::DownloadFailed(path, _) = status else {
+                return;
+            };
+            if let Some((downloaded, total)) = state.downloads.get_mut(path) {
+                state.total -= total.unwrap_or(*downloaded);
+                state.total += *downloaded;
+                *total = Some(*downloaded);
+            }
+        })
+    }
+
+    fn task_finished(&self, _task: &TaskGroupTask) {
+        self.update(|state| state.tasks_done += 1)
+    }
+}
+
+/// Exit code used when some, but not all, artifacts were fetched.
+const PARTIAL_FAILURE: u8 = 2;
+
+/// Exit code used by the status command when some tasks are still pending.
+const PENDING: u8 = 3;
+
+fn fetch(matches: &ArgMatches, config: &Config, multi: &MultiProgress) -> Result<ExitCode> {
+    let project = project(matches, config)?;
+    let source = task_source(matches);
     let taskcluster_base = matches.get_one::<String>("taskcluster_url");
-    let artifact_name = matches.get_one::<String>("artifact_name");
-    let task_filter_strs = matches.get_many::<String>("filter_re");
     let check_complete = matches.get_flag("check_complete");
-    let compress = matches.get_flag("compress");
 
     let cur_dir = env::current_dir().expect("Invalid working directory");
     let out_dir: PathBuf = if let Some(dir) = matches.get_one::<String>("out_dir") {
         cur_dir.join(dir)
     } else {
-        cur_dir
+        cur_dir.clone()
     };
+    let manifest = matches
+        .get_one::<String>("manifest")
+        .map(|path| cur_dir.join(path));
     if !out_dir.is_dir() {
         return Err(Error::String(format!(
             "{} is not a directory",
@@ -81,40 +725,386 @@ fn run() -> Result<()> {
         )));
     }
 
-    let task_filters = task_filter_strs
-        .map(|filters| {
-            filters
-                .map(|filter| TaskFilter::new(filter))
-                .collect::<Result<Vec<TaskFilter>>>()
-        })
-        .transpose()?;
+    let artifact_selectors = artifact_selectors(matches)?;
+    let default_artifacts = artifact_selectors.is_none();
+    let progress = (!matches.get_flag("no_progress")).then(|| Arc::new(FetchProgress::new(multi)));
 
-    let downloaded = download_artifacts(
+    let results = download_artifacts(
+        &client(matches, config)?,
         taskcluster_base.map(|x| x.as_str()),
-        repo,
-        commit,
-        task_filters,
-        artifact_name.map(|x| x.as_str()),
-        check_complete,
+        &project,
+        &source,
         &out_dir,
-        compress,
-    )?;
-    if downloaded.is_empty() {
-        let suffix = if artifact_name.is_none() {
-            " (consider --artifact-name if you aren't downloading wptreport logs)"
+        DownloadOptions {
+            selection: task_selection(matches)?,
+            artifacts: artifact_selectors,
+            check_complete,
+            runs: matches
+                .get_one::<String>("runs")
+                .map(|value| value.parse())
+                .transpose()?,
+            output_template: output_template(matches, config)?,
+            encoding: output_encoding(matches)?,
+            manifest,
+            wait: wait_options(matches)?,
+            cache: cache_config(matches, config)?.artifact_cache()?,
+            concurrency: concurrency(matches),
+            progress: progress
+                .clone()
+                .map(|progress| progress as Arc<dyn ProgressObserver>),
+        },
+    );
+    if let Some(progress) = progress {
+        progress.bar.finish_and_clear();
+        multi.remove(&progress.bar);
+    }
+    let results = results?;
+    if matches.get_flag("json") {
+        write_json(&json!(results
+            .iter()
+            .map(|result| json!({
+                "taskId": result.task.status.taskId,
+                "runId": result.run_id,
+                "name": result.task.task.metadata.name,
+                "artifact": result.artifact.as_ref().map(|artifact| &artifact.name),
+                "status": result.status.as_str(),
+                "path": result.status.path(),
+                "error": result.status.error().map(|err| err.to_string()),
+                "sha256": result.sha256,
+            }))
+            .collect::<Vec<_>>()))?;
+    }
+
+    let mut downloaded = 0;
+    let mut cached = 0;
+    let mut skipped = 0;
+    let mut missing = 0;
+    let mut failed = Vec::new();
+    for result in results.iter() {
+        match result.status {
+            DownloadStatus::Downloaded(_) => downloaded += 1,
+            DownloadStatus::Cached(_) => cached += 1,
+            DownloadStatus::SkippedExisting(_) => skipped += 1,
+            DownloadStatus::ArtifactMissing => missing += 1,
+            DownloadStatus::ListingFailed(_)
+            | DownloadStatus::DownloadFailed(_, _)
+            | DownloadStatus::TimedOut(_) => failed.push(result),
+        }
+    }
+    info!(
+        "{} artifacts downloaded, {} from the cache, {} already present, {} tasks without a matching artifact, {} failed",
+        downloaded,
+        cached,
+        skipped,
+        missing,
+        failed.len()
+    );
+    if downloaded + cached + skipped == 0 {
+        let suffix = if default_artifacts {
+            " (consider --artifact-name if you aren't downloading the default artifact)"
         } else {
             ""
         };
         error!("No logs found{}", suffix);
     }
+    if !failed.is_empty() {
+        for result in failed {
+            let artifact = result
+                .artifact
+                .as_ref()
+                .map(|artifact| format!(" {}", artifact.name))
+                .unwrap_or_default();
+            error!(
+                "Failed to fetch{} from {} ({}): {}",
+                artifact,
+                result.task.task.metadata.name,
+                result.task.status.taskId,
+                result.status.error().unwrap()
+            );
+        }
+        return Ok(ExitCode::from(PARTIAL_FAILURE));
+    }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+fn status(matches: &ArgMatches, config: &Config) -> Result<ExitCode> {
+    let project = project(matches, config)?;
+    let source = task_source(matches);
+    let taskcluster_base = matches.get_one::<String>("taskcluster_url");
+
+    let tasks = get_tasks(
+        &client(matches, config)?,
+        taskcluster_base.map(|x| x.as_str()),
+        &project,
+        &source,
+        &task_selection(matches)?,
+    )?;
+    let mut states = BTreeMap::new();
+    for task in tasks.iter() {
+        *states.entry(task.status.state.as_str()).or_insert(0) += 1;
+    }
+    let complete = tasks_complete(tasks.iter());
+    let complete_count = tasks
+        .iter()
+        .filter(|task| task.status.state.is_complete())
+        .count();
+
+    if matches.get_flag("json") {
+        write_json(&json!({
+            "complete": complete,
+            "total": tasks.len(),
+            "states": states,
+        }))?;
+    } else {
+        for (state, count) in states.iter() {
+            println!("{}: {}", state, count);
+        }
+        println!("{} of {} tasks complete", complete_count, tasks.len());
+    }
+    Ok(if complete {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(PENDING)
+    })
+}
+
+fn list_tasks(matches: &ArgMatches, config: &Config) -> Result<ExitCode> {
+    let project = project(matches, config)?;
+    let source = task_source(matches);
+    let taskcluster_base = matches.get_one::<String>("taskcluster_url");
+
+    let tasks = get_tasks(
+        &client(matches, config)?,
+        taskcluster_base.map(|x| x.as_str()),
+        &project,
+        &source,
+        &task_selection(matches)?,
+    )?;
+    if matches.get_flag("json") {
+        write_json(&json!(tasks
+            .iter()
+            .map(|task| json!({
+                "taskId": task.status.taskId,
+                "taskGroupId": task.status.taskGroupId,
+                "name": task.task.metadata.name,
+                "state": task.status.state,
+                "testSetting": task.test_setting(),
+            }))
+            .collect::<Vec<_>>()))?;
+    } else {
+        for task in tasks.iter() {
+            println!(
+                "{}\t{}\t{}",
+                task.status.taskId,
+                task.status.state.as_str(),
+                task.task.metadata.name
+            );
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn list_task_artifacts(matches: &ArgMatches, config: &Config) -> Result<ExitCode> {
+    let project = project(matches, config)?;
+    let source = task_source(matches);
+    let taskcluster_base = matches.get_one::<String>("taskcluster_url");
+
+    let task_artifacts = list_artifacts(
+        &client(matches, config)?,
+        taskcluster_base.map(|x| x.as_str()),
+        &project,
+        &source,
+        &task_selection(matches)?,
+        artifact_selectors(matches)?,
+    )?;
+    if matches.get_flag("json") {
+        write_json(&json!(task_artifacts
+            .iter()
+            .map(|(task, artifacts)| json!({
+                "taskId": task.status.taskId,
+                "name": task.task.metadata.name,
+                "artifacts": artifacts,
+            }))
+            .collect::<Vec<_>>()))?;
+    } else {
+        for (task, artifacts) in task_artifacts.iter() {
+            println!("{} ({})", task.task.metadata.name, task.status.taskId);
+            for artifact in artifacts.iter() {
+                println!("  {}", artifact.name);
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn verify(matches: &ArgMatches, config: &Config) -> Result<ExitCode> {
+    let mut manifest = Manifest::load(Path::new(matches.get_one::<String>("manifest").unwrap()))?;
+    if let Some(dir) = matches.get_one::<String>("out_dir") {
+        manifest.out_dir = PathBuf::from(dir);
+    }
+    let project = config.project(&manifest.repo)?;
+    let results = verify_manifest(
+        &client(matches, config)?,
+        matches
+            .get_one::<String>("taskcluster_url")
+            .map(|x| x.as_str()),
+        &project,
+        manifest,
+        matches.get_flag("check_taskcluster"),
+    )?;
+
+    if matches.get_flag("remove_invalid") {
+        for result in results.iter() {
+            if matches!(
+                result.status,
+                VerifyStatus::SizeMismatch { .. }
+                    | VerifyStatus::Sha256Mismatch { .. }
+                    | VerifyStatus::TaskclusterMismatch { .. }
+            ) {
+                info!("Removing {}", result.path.display());
+                fs::remove_file(&result.path)?;
+            }
+        }
+    }
+
+    if matches.get_flag("json") {
+        write_json(&json!(results
+            .iter()
+            .map(|result| json!({
+                "taskId": result.entry.task_id,
+                "runId": result.entry.run_id,
+                "artifact": result.entry.artifact,
+                "path": result.path,
+                "status": result.status.as_str(),
+                "error": result.status.message(),
+            }))
+            .collect::<Vec<_>>()))?;
+    }
+
+    let mut valid = 0;
+    let mut verified = 0;
+    let mut invalid = 0;
+    for result in results.iter() {
+        match result.status {
+            VerifyStatus::Ok => valid += 1,
+            VerifyStatus::Verified => verified += 1,
+            _ => {
+                invalid += 1;
+                error!(
+                    "{} ({}): {}",
+                    result.path.display(),
+                    result.entry.artifact,
+                    result.status.message().unwrap()
+                );
+            }
+        }
+    }
+    info!(
+        "{} files match the manifest, {} of them also matching Taskcluster's SHA-256, {} invalid",
+        valid + verified,
+        verified,
+        invalid
+    );
+    Ok(if invalid == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn cache(matches: &ArgMatches, config: &Config) -> Result<ExitCode> {
+    let cache_config = cache_config(matches, config)?;
+    let (Some(artifact_cache), Some(response_cache)) = (
+        cache_config.artifact_cache()?,
+        cache_config.response_cache(),
+    ) else {
+        return Err(Error::String(
+            "Caching is disabled, or there's no cache directory".into(),
+        ));
+    };
+    match matches.subcommand() {
+        Some(("stats", _)) => {
+            let artifacts = artifact_cache.stats()?;
+            let responses = response_cache.stats()?;
+            if matches.get_flag("json") {
+                write_json(&json!({
+                    "artifacts": artifacts,
+                    "responses": responses,
+                }))?;
+            } else {
+                let max_size = artifacts
+                    .max_size
+                    .map(|size| size.to_string())
+                    .unwrap_or_else(|| "none".into());
+                println!("Artifact cache: {}", artifacts.dir.display());
+                println!("  Artifacts: {}", artifacts.entries);
+                println!("  Files: {}", artifacts.objects);
+                println!("  Size: {}", artifacts.size);
+                println!("  Maximum size: {}", max_size);
+                println!("Response cache: {}", responses.dir.display());
+                println!("  Responses: {}", responses.entries);
+                println!("  Size: {}", responses.size);
+            }
+        }
+        Some(("gc", _)) => {
+            let artifacts = artifact_cache.gc()?;
+            let removed_responses = response_cache.gc()?;
+            if matches.get_flag("json") {
+                write_json(&json!({
+                    "artifacts": artifacts,
+                    "removedResponses": removed_responses,
+                }))?;
+            } else {
+                println!(
+                    "Removed {} artifacts and {} files, freeing {} bytes",
+                    artifacts.removed_entries, artifacts.removed_objects, artifacts.freed
+                );
+                println!("Artifact cache size: {}", artifacts.size);
+                println!("Removed {} cached responses", removed_responses);
+            }
+        }
+        _ => unreachable!("Subcommand is required"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Set up logging so that log messages are written above any progress bars
+/// in the returned `MultiProgress`.
+fn init_logging() -> MultiProgress {
+    let logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).build();
+    let level = logger.filter();
+    let multi = MultiProgress::new();
+    LogWrapper::new(multi.clone(), logger)
+        .try_init()
+        .expect("Logger already initialized");
+    log::set_max_level(level);
+    multi
+}
+
+fn run() -> Result<ExitCode> {
+    let multi = init_logging();
+
+    let matches = parse_args().get_matches();
+    let config = Config::load(matches.get_one::<String>("config").map(Path::new))?;
+    match matches.subcommand() {
+        Some(("fetch", matches)) => fetch(matches, &config, &multi),
+        Some(("status", matches)) => status(matches, &config),
+        Some(("list-tasks", matches)) => list_tasks(matches, &config),
+        Some(("list-artifacts", matches)) => list_task_artifacts(matches, &config),
+        Some(("verify", matches)) => verify(matches, &config),
+        Some(("cache", matches)) => cache(matches, &config),
+        _ => unreachable!("Subcommand is required"),
+    }
 }
 
-fn main() -> Result<()> {
-    let result = run();
-    if let Err(ref error) = result {
-        error!("{}", error);
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(error) => {
+            error!("{}", error);
+            ExitCode::FAILURE
+        }
     }
-    result
 }
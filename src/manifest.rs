@@ -0,0 +1,130 @@
+use crate::encoding::OutputEncoding;
+use crate::utils::sha256_file;
+use crate::{Result, TaskDownload, TaskSource};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Description of a completed fetch, written as JSON alongside the artifacts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub repo: String,
+    #[serde(flatten)]
+    pub source: TaskSource,
+    /// Directory containing the fetched files. It's written relative to the
+    /// manifest's directory if it's inside that, so the two can be moved
+    /// together.
+    pub out_dir: PathBuf,
+    pub artifacts: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    pub task_id: String,
+    pub task_group_id: String,
+    pub label: String,
+    pub run_id: Option<u64>,
+    pub artifact: String,
+    pub content_type: String,
+    pub expires: String,
+    /// Path of the local file, relative to `out_dir`.
+    pub path: PathBuf,
+    /// Size in bytes of the local file.
+    pub size: u64,
+    /// SHA-256 of the local file, as a hex string.
+    pub sha256: String,
+    /// Output encoding the local file was written with.
+    pub encoding: String,
+    /// SHA-256 of the artifact content before it was encoded, if known.
+    pub content_sha256: Option<String>,
+}
+
+impl Manifest {
+    /// Build a manifest from the artifacts that are available locally.
+    pub fn new(
+        repo: &str,
+        source: &TaskSource,
+        out_dir: &Path,
+        downloads: &[TaskDownload],
+        encoding: &OutputEncoding,
+    ) -> Result<Manifest> {
+        let mut artifacts = Vec::new();
+        for download in downloads {
+            let (Some(artifact), Some(path)) = (&download.artifact, download.status.path()) else {
+                continue;
+            };
+            let task = &download.task;
+            let sha256 = sha256_file(path)?;
+            let content_sha256 = download
+                .sha256
+                .clone()
+                .or_else(|| (*encoding == OutputEncoding::Raw).then(|| sha256.clone()));
+            artifacts.push(ManifestEntry {
+                task_id: task.status.taskId.clone(),
+                task_group_id: task.status.taskGroupId.clone(),
+                label: task.task.metadata.name.clone(),
+                run_id: download.run_id,
+                artifact: artifact.name.clone(),
+                content_type: artifact.contentType.clone(),
+                expires: artifact.expires.clone(),
+                path: path.strip_prefix(out_dir).unwrap_or(path).to_path_buf(),
+                size: path.metadata()?.len(),
+                sha256,
+                encoding: encoding.as_str().into(),
+                content_sha256,
+            });
+        }
+        Ok(Manifest {
+            repo: repo.into(),
+            source: source.clone(),
+            out_dir: out_dir.to_path_buf(),
+            artifacts,
+        })
+    }
+
+    /// Read a manifest written by `write`.
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let mut manifest: Manifest = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if manifest.out_dir.is_relative() {
+            let manifest_dir = path.parent().unwrap_or(Path::new(""));
+            manifest.out_dir = manifest_dir.join(&manifest.out_dir);
+        }
+        Ok(manifest)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let out_dir = relative_out_dir(&self.out_dir, path);
+        serde_json::to_writer_pretty(
+            &mut writer,
+            &Manifest {
+                out_dir,
+                ..self.clone()
+            },
+        )?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `out_dir` relative to the directory containing the manifest at
+/// `manifest_path`, if it's inside that directory.
+fn relative_out_dir(out_dir: &Path, manifest_path: &Path) -> PathBuf {
+    let manifest_dir = match manifest_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (Ok(abs_out_dir), Ok(manifest_dir)) = (out_dir.canonicalize(), manifest_dir.canonicalize())
+    else {
+        return out_dir.to_path_buf();
+    };
+    match abs_out_dir.strip_prefix(manifest_dir) {
+        Ok(relative) if relative.as_os_str().is_empty() => PathBuf::from("."),
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => out_dir.to_path_buf(),
+    }
+}
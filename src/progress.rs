@@ -0,0 +1,66 @@
+use crate::taskcluster::{Artifact, TaskGroupTask};
+use crate::DownloadStatus;
+use std::fmt;
+use std::path::Path;
+
+/// Receives notifications as artifacts are fetched, e.g. to display a
+/// progress bar. All methods do nothing by default.
+///
+/// Artifacts are fetched concurrently, so notifications for different tasks
+/// and downloads are interleaved.
+pub trait ProgressObserver: Send + Sync {
+    /// Artifacts will be fetched from `count` more tasks. When waiting for
+    /// tasks to complete this is called for each batch of completed tasks.
+    fn tasks_found(&self, _count: usize) {}
+
+    /// `count` artifacts from a run of `task` matched the artifact selectors.
+    fn artifacts_found(&self, _task: &TaskGroupTask, _count: usize) {}
+
+    /// A download to `dest` started, or was restarted after an error, with
+    /// `downloaded` bytes already fetched by an earlier attempt. `total` is
+    /// the size of the whole download, if the server sent a Content-Length.
+    fn download_started(&self, _dest: &Path, _downloaded: u64, _total: Option<u64>) {}
+
+    /// Another `bytes` bytes of the download to `dest` were received.
+    fn bytes_downloaded(&self, _dest: &Path, _bytes: u64) {}
+
+    /// Fetching `artifact` of `task` finished with `status`.
+    fn artifact_finished(
+        &self,
+        _task: &TaskGroupTask,
+        _artifact: &Artifact,
+        _status: &DownloadStatus,
+    ) {
+    }
+
+    /// Everything selected from `task` was fetched, or listing its artifacts
+    /// failed.
+    fn task_finished(&self, _task: &TaskGroupTask) {}
+}
+
+impl fmt::Debug for dyn ProgressObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressObserver")
+    }
+}
+
+/// Observer that ignores all notifications.
+impl ProgressObserver for () {}
+
+/// Progress of a single download, reported under the path of the artifact
+/// being fetched rather than any staging file it's downloaded to.
+#[derive(Clone, Copy)]
+pub(crate) struct DownloadProgress<'a> {
+    pub observer: &'a dyn ProgressObserver,
+    pub dest: &'a Path,
+}
+
+impl DownloadProgress<'_> {
+    pub(crate) fn started(&self, downloaded: u64, total: Option<u64>) {
+        self.observer.download_started(self.dest, downloaded, total)
+    }
+
+    pub(crate) fn advanced(&self, bytes: u64) {
+        self.observer.bytes_downloaded(self.dest, bytes)
+    }
+}
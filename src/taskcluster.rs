@@ -1,21 +1,33 @@
-use crate::utils::{get_json, url};
-use crate::{Result, TaskFilter};
-use reqwest;
-use serde_derive::Deserialize;
+use crate::hawk;
+use crate::utils::{get_json_with_policy, url, AsyncClient, CachePolicy, Client};
+use crate::{Error, Result, TaskFilter};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
 
 pub(crate) trait TaskclusterCI {
-    fn default_artifact_name(&self) -> &'static str;
-    fn default_task_filter(&self) -> Vec<TaskFilter>;
+    fn default_artifact_name(&self) -> &str;
+    fn default_task_filter(&self) -> Result<Vec<TaskFilter>>;
+    /// Task group ids for a commit, including those of action tasks if
+    /// `include_actions` is set.
     fn get_taskgroups(
         &self,
-        client: &reqwest::blocking::Client,
+        client: &Client,
         commit: &str,
+        include_actions: bool,
     ) -> Result<Vec<String>>;
     fn taskcluster(&self) -> &Taskcluster;
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TaskState {
     Unscheduled,
@@ -27,6 +39,17 @@ pub enum TaskState {
 }
 
 impl TaskState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskState::Unscheduled => "unscheduled",
+            TaskState::Pending => "pending",
+            TaskState::Running => "running",
+            TaskState::Completed => "completed",
+            TaskState::Failed => "failed",
+            TaskState::Exception => "exception",
+        }
+    }
+
     pub fn is_complete(&self) -> bool {
         match self {
             TaskState::Unscheduled | TaskState::Pending | TaskState::Running => false,
@@ -35,6 +58,22 @@ impl TaskState {
     }
 }
 
+impl FromStr for TaskState {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<TaskState> {
+        match value {
+            "unscheduled" => Ok(TaskState::Unscheduled),
+            "pending" => Ok(TaskState::Pending),
+            "running" => Ok(TaskState::Running),
+            "completed" => Ok(TaskState::Completed),
+            "failed" => Ok(TaskState::Failed),
+            "exception" => Ok(TaskState::Exception),
+            _ => Err(Error::String(format!("Unknown task state {}", value))),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
 pub struct IndexResponse {
@@ -44,13 +83,20 @@ pub struct IndexResponse {
     pub expires: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct IndexListResponse {
+    pub tasks: Vec<IndexResponse>,
+    pub continuationToken: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
 pub struct ArtifactsResponse {
     pub artifacts: Vec<Artifact>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct Artifact {
     pub storageType: String,
@@ -59,6 +105,47 @@ pub struct Artifact {
     pub contentType: String,
 }
 
+/// Artifact written by tasks that support chain of trust, listing the
+/// SHA-256 of each of the task's other artifacts.
+pub const CHAIN_OF_TRUST_ARTIFACT: &str = "public/chain-of-trust.json";
+
+#[derive(Debug, Deserialize)]
+struct ChainOfTrust {
+    artifacts: BTreeMap<String, ChainOfTrustArtifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChainOfTrustArtifact {
+    sha256: Option<String>,
+}
+
+/// Queue response for an artifact stored in the object service.
+#[derive(Debug, Deserialize)]
+struct ObjectArtifact {
+    name: String,
+    credentials: Credentials,
+}
+
+#[derive(Debug, Deserialize)]
+struct StartDownloadResponse {
+    method: String,
+    url: String,
+    #[serde(default)]
+    hashes: BTreeMap<String, String>,
+}
+
+/// Where to download an artifact stored in the object service, and the
+/// SHA-256 of its content if the object service has it.
+pub(crate) struct ObjectDownload {
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TaskStatusResponse {
+    pub status: TaskGroupTaskStatus,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
 pub struct TaskGroupResponse {
@@ -67,14 +154,14 @@ pub struct TaskGroupResponse {
     pub continuationToken: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct TaskGroupTask {
     pub status: TaskGroupTaskStatus,
     pub task: Task,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct TaskGroupTaskStatus {
     pub taskId: String,
@@ -89,7 +176,7 @@ pub struct TaskGroupTaskStatus {
     pub runs: Vec<TaskRun>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct TaskRun {
     pub runId: u64,
@@ -104,19 +191,23 @@ pub struct TaskRun {
     pub resolved: Option<String>,   // Should be a time type
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct Task {
     pub provisionerId: String,
     pub workerType: String,
     pub schedulerId: String,
     pub taskGroupId: String,
+    #[serde(default)]
+    pub created: String, // Should be a time type
     pub metadata: TaskMetadata,
     #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
     pub extra: BTreeMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct TaskMetadata {
     pub owner: String,
@@ -125,64 +216,482 @@ pub struct TaskMetadata {
     pub name: String,
 }
 
+/// The configuration a Gecko test task runs in, from `extra["test-setting"]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct TestSetting {
+    pub platform: TestPlatform,
+    pub build: TestBuild,
+    /// Runtime variants, e.g. `fission`.
+    #[serde(default)]
+    pub runtime: BTreeMap<String, bool>,
+    /// Hash identifying the test setting.
+    #[serde(rename = "_hash", default)]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct TestPlatform {
+    pub os: TestOs,
+    pub arch: String,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub display: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct TestOs {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub build: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct TestBuild {
+    /// Build type, e.g. `opt` or `debug`.
+    #[serde(rename = "type")]
+    pub build_type: String,
+    /// Build variants, e.g. `asan` or `shippable`.
+    #[serde(flatten)]
+    pub flags: BTreeMap<String, bool>,
+}
+
+impl TestSetting {
+    /// Names of the enabled build variants.
+    pub fn build_flags(&self) -> Vec<&str> {
+        enabled(&self.build.flags)
+    }
+
+    /// Names of the enabled runtime variants.
+    pub fn runtime_flags(&self) -> Vec<&str> {
+        enabled(&self.runtime)
+    }
+
+    /// Short description of the setting, e.g. `linux-22.04-64/opt-asan fission`.
+    pub fn display_name(&self) -> String {
+        let platform = &self.platform;
+        let mut name = platform.os.name.clone();
+        for part in [
+            &platform.os.version,
+            &Some(platform.arch.clone()),
+            &platform.device,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            name.push('-');
+            name.push_str(part);
+        }
+        name.push('/');
+        name.push_str(&self.build.build_type);
+        for flag in self.build_flags() {
+            name.push('-');
+            name.push_str(flag);
+        }
+        for flag in self.runtime_flags() {
+            name.push(' ');
+            name.push_str(flag);
+        }
+        name
+    }
+}
+
+fn enabled(flags: &BTreeMap<String, bool>) -> Vec<&str> {
+    flags
+        .iter()
+        .filter(|(_, enabled)| **enabled)
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+impl TaskGroupTask {
+    /// The test setting of a Gecko test task, if it has one in the expected
+    /// format.
+    pub fn test_setting(&self) -> Option<TestSetting> {
+        self.task
+            .extra
+            .get("test-setting")
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+}
+
+/// Taskcluster client credentials.
+#[derive(Clone, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Credentials {
+    pub clientId: String,
+    pub accessToken: String,
+    /// Certificate for temporary credentials, as a JSON string.
+    pub certificate: Option<String>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("clientId", &self.clientId)
+            .field("accessToken", &"<redacted>")
+            .field(
+                "certificate",
+                &self.certificate.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// Read credentials from the `TASKCLUSTER_CLIENT_ID`,
+    /// `TASKCLUSTER_ACCESS_TOKEN` and `TASKCLUSTER_CERTIFICATE` environment
+    /// variables.
+    pub fn from_env() -> Option<Credentials> {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        Some(Credentials {
+            clientId: var("TASKCLUSTER_CLIENT_ID")?,
+            accessToken: var("TASKCLUSTER_ACCESS_TOKEN")?,
+            certificate: var("TASKCLUSTER_CERTIFICATE"),
+        })
+    }
+
+    /// Read credentials from a JSON file with `clientId`, `accessToken` and
+    /// optionally `certificate` keys.
+    pub fn from_file(path: &Path) -> Result<Credentials> {
+        let file = File::open(path).map_err(|err| {
+            Error::String(format!(
+                "Failed to read credentials from {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
 pub struct Taskcluster {
     pub index_base: String,
     pub queue_base: String,
+    /// Base URL of the object service, which the legacy deployment lacks.
+    pub object_base: Option<String>,
+}
+
+/// Queue API path of a task, or of one of its runs.
+fn task_path(task_id: &str, run_id: Option<u64>) -> String {
+    match run_id {
+        Some(run_id) => format!("task/{}/runs/{}", task_id, run_id),
+        None => format!("task/{}", task_id),
+    }
+}
+
+/// URL of a Taskcluster API endpoint.
+fn api_url(base: &str, path: &str, query: Vec<(&str, String)>) -> Result<Url> {
+    let api_url = url(base, path);
+    let result = if query.is_empty() {
+        Url::parse(&api_url)
+    } else {
+        Url::parse_with_params(&api_url, query)
+    };
+    result.map_err(|err| Error::String(format!("Invalid url {}: {}", api_url, err)))
 }
 
+/// Authorization header for a request to `url`, if the client has
+/// Taskcluster credentials.
+fn auth_headers(client: &Client, url: &Url) -> Result<Option<Vec<(String, String)>>> {
+    client
+        .taskcluster_credentials()
+        .map(|credentials| {
+            hawk::authorization_header(credentials, "GET", url)
+                .map(|header| vec![("Authorization".to_string(), header)])
+        })
+        .transpose()
+}
+
+/// Lifetime of the signed URLs used to download private artifacts.
+const SIGNED_URL_TTL_SECS: u64 = 60 * 60;
+
 impl Taskcluster {
     pub fn new(taskcluster_base: &str) -> Taskcluster {
         if taskcluster_base == "https://taskcluster.net" {
             Taskcluster {
                 index_base: "https://index.taskcluster.net/v1/".into(),
                 queue_base: "https://queue.taskcluster.net/v1/".into(),
+                object_base: None,
             }
         } else {
             Taskcluster {
                 index_base: format!("{}/api/index/v1/", taskcluster_base),
                 queue_base: format!("{}/api/queue/v1/", taskcluster_base),
+                object_base: Some(format!("{}/api/object/v1/", taskcluster_base)),
             }
         }
     }
 
+    /// Get JSON from a Taskcluster API, signing the request if the client has
+    /// Taskcluster credentials.
+    fn get_json<T>(&self, client: &Client, url: &Url, policy: CachePolicy) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        get_json_with_policy(
+            client,
+            url.as_str(),
+            None,
+            || auth_headers(client, url),
+            policy,
+        )
+    }
+
+    /// Find the task at the given index path, e.g.
+    /// `gecko.v2.mozilla-central.latest.taskgraph.decision`.
+    pub fn find_indexed_task(&self, client: &Client, index_path: &str) -> Result<IndexResponse> {
+        self.get_json(
+            client,
+            &api_url(&self.index_base, &format!("task/{}", index_path), vec![])?,
+            CachePolicy::Revalidate,
+        )
+    }
+
+    /// List the tasks indexed directly under `namespace`.
+    pub fn list_indexed_tasks(
+        &self,
+        client: &Client,
+        namespace: &str,
+    ) -> Result<Vec<IndexResponse>> {
+        let url_suffix = format!("tasks/{}", namespace);
+        let mut tasks = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let query = continuation_token
+                .map(|token| vec![("continuationToken", token)])
+                .unwrap_or_default();
+            let data: IndexListResponse = self.get_json(
+                client,
+                &api_url(&self.index_base, &url_suffix, query)?,
+                CachePolicy::Revalidate,
+            )?;
+            tasks.extend(data.tasks);
+            if data.continuationToken.is_none() {
+                break;
+            }
+            continuation_token = data.continuationToken;
+        }
+        Ok(tasks)
+    }
+
     pub fn get_taskgroup_tasks(
         &self,
-        client: &reqwest::blocking::Client,
+        client: &Client,
         taskgroup_id: &str,
     ) -> Result<Vec<TaskGroupTask>> {
         let url_suffix = format!("task-group/{}/list", taskgroup_id);
         let mut tasks = Vec::new();
+        let mut page_urls = Vec::new();
         let mut continuation_token: Option<String> = None;
         loop {
-            let query = continuation_token.map(|token| vec![("continuationToken".into(), token)]);
+            let query = continuation_token
+                .map(|token| vec![("continuationToken", token)])
+                .unwrap_or_default();
+            let page_url = api_url(&self.queue_base, &url_suffix, query)?;
             let data: TaskGroupResponse =
-                get_json(client, &url(&self.queue_base, &url_suffix), query, None)?;
+                self.get_json(client, &page_url, CachePolicy::Revalidate)?;
+            page_urls.push(page_url);
             tasks.extend(data.tasks);
             if data.continuationToken.is_none() {
                 break;
             }
             continuation_token = data.continuationToken;
         }
+        // Once every task is resolved the listing won't change, so it can be
+        // reused without asking the server.
+        if let Some(cache) = client.response_cache() {
+            if !tasks.is_empty() && tasks_complete(tasks.iter()) {
+                for page_url in page_urls {
+                    cache.mark_immutable(page_url.as_str())?;
+                }
+            }
+        }
         Ok(tasks)
     }
 
-    pub fn get_artifacts(
+    /// Get the definition and status of a single task.
+    pub fn get_task(&self, client: &Client, task_id: &str) -> Result<TaskGroupTask> {
+        // Task definitions can't be changed once created.
+        let task: Task = self.get_json(
+            client,
+            &api_url(&self.queue_base, &format!("task/{}", task_id), vec![])?,
+            CachePolicy::Immutable,
+        )?;
+        let status: TaskStatusResponse = self.get_json(
+            client,
+            &api_url(
+                &self.queue_base,
+                &format!("task/{}/status", task_id),
+                vec![],
+            )?,
+            CachePolicy::Revalidate,
+        )?;
+        Ok(TaskGroupTask {
+            status: status.status,
+            task,
+        })
+    }
+
+    /// URL listing the artifacts of a task, and whether the listing can change.
+    fn artifacts_url(
+        &self,
+        task: &TaskGroupTask,
+        run_id: Option<u64>,
+    ) -> Result<(Url, CachePolicy)> {
+        // The artifacts of a resolved run can't change, but a resolved
+        // latest run may be superseded by a rerun, so it's named explicitly.
+        let resolved_run = match run_id {
+            Some(run_id) => task.status.runs.iter().find(|run| run.runId == run_id),
+            None => task.status.runs.last(),
+        }
+        .filter(|run| run.state.is_complete());
+        let (run_id, policy) = match resolved_run {
+            Some(run) => (Some(run.runId), CachePolicy::Immutable),
+            None => (run_id, CachePolicy::Revalidate),
+        };
+        let url_suffix = format!("{}/artifacts", task_path(&task.status.taskId, run_id));
+        Ok((api_url(&self.queue_base, &url_suffix, vec![])?, policy))
+    }
+
+    /// List the artifacts of a task, from the given run or otherwise the latest run.
+    pub(crate) async fn get_artifacts_async(
+        &self,
+        client: &AsyncClient<'_>,
+        task: &TaskGroupTask,
+        run_id: Option<u64>,
+    ) -> Result<Vec<Artifact>> {
+        let (url, policy) = self.artifacts_url(task, run_id)?;
+        let artifacts: ArtifactsResponse = client
+            .get_json(url.as_str(), || auth_headers(client.client(), &url), policy)
+            .await?;
+        Ok(artifacts.artifacts)
+    }
+
+    /// List the artifacts of a run given only its task id.
+    pub(crate) async fn get_run_artifacts(
         &self,
-        client: &reqwest::blocking::Client,
+        client: &AsyncClient<'_>,
         task_id: &str,
+        run_id: u64,
     ) -> Result<Vec<Artifact>> {
-        let url_suffix = format!("task/{}/artifacts", task_id);
-        let artifacts: ArtifactsResponse =
-            get_json(client, &url(&self.queue_base, &url_suffix), None, None)?;
+        let url = api_url(
+            &self.queue_base,
+            &format!("{}/artifacts", task_path(task_id, Some(run_id))),
+            vec![],
+        )?;
+        let artifacts: ArtifactsResponse = client
+            .get_json(
+                url.as_str(),
+                || auth_headers(client.client(), &url),
+                CachePolicy::Revalidate,
+            )
+            .await?;
         Ok(artifacts.artifacts)
     }
 
-    pub fn get_log_url(&self, task_id: &str, artifact: &Artifact) -> String {
-        let task_url = format!("task/{}/artifacts", task_id);
-        url(
+    /// SHA-256 hashes of the artifacts of a run, from its chain of trust
+    /// artifact, keyed by artifact name.
+    pub(crate) async fn get_chain_of_trust(
+        &self,
+        client: &AsyncClient<'_>,
+        task_id: &str,
+        run_id: u64,
+    ) -> Result<BTreeMap<String, String>> {
+        let url = api_url(
+            &self.queue_base,
+            &format!(
+                "{}/artifacts/{}",
+                task_path(task_id, Some(run_id)),
+                CHAIN_OF_TRUST_ARTIFACT
+            ),
+            vec![],
+        )?;
+        // The chain of trust is only written once the run has finished.
+        let chain_of_trust: ChainOfTrust = client
+            .get_json(url.as_str(), || Ok(None), CachePolicy::Immutable)
+            .await?;
+        Ok(chain_of_trust
+            .artifacts
+            .into_iter()
+            .filter_map(|(name, artifact)| artifact.sha256.map(|sha256| (name, sha256)))
+            .collect())
+    }
+
+    /// Where to download an artifact stored in the object service, from the
+    /// given run or otherwise the latest run.
+    pub(crate) async fn get_object_download(
+        &self,
+        client: &AsyncClient<'_>,
+        task_id: &str,
+        run_id: Option<u64>,
+        artifact: &Artifact,
+    ) -> Result<ObjectDownload> {
+        let object_base = self.object_base.as_ref().ok_or_else(|| {
+            Error::String(format!(
+                "{} is stored in the object service, which isn't available",
+                artifact.name
+            ))
+        })?;
+        // The queue responds with credentials for fetching the object.
+        let artifact_url = self.get_log_url(client.client(), task_id, run_id, artifact)?;
+        let object: ObjectArtifact = client
+            .get_json(&artifact_url, || Ok(None), CachePolicy::Uncached)
+            .await?;
+        let url = api_url(
+            object_base,
+            &format!("start-download/{}", object.name),
+            vec![],
+        )?;
+        let download: StartDownloadResponse = client
+            .put_json(
+                &url,
+                &object.credentials,
+                &json!({"acceptDownloadMethods": {"getUrl": true}}),
+            )
+            .await?;
+        if download.method != "getUrl" {
+            return Err(Error::String(format!(
+                "Unsupported download method {} for {}",
+                download.method, artifact.name
+            )));
+        }
+        Ok(ObjectDownload {
+            url: download.url,
+            sha256: download.hashes.get("sha256").cloned(),
+        })
+    }
+
+    /// URL from which to download an artifact, from the given run or otherwise
+    /// the latest run. If the client has credentials then URLs for non-public
+    /// artifacts are signed.
+    pub fn get_log_url(
+        &self,
+        client: &Client,
+        task_id: &str,
+        run_id: Option<u64>,
+        artifact: &Artifact,
+    ) -> Result<String> {
+        let task_url = format!("{}/artifacts", task_path(task_id, run_id));
+        let artifact_url = url(
             &self.queue_base,
             &format!("{}/{}", &task_url, artifact.name),
-        )
+        );
+        match client.taskcluster_credentials() {
+            Some(credentials) if !artifact.name.starts_with("public/") => {
+                let artifact_url = Url::parse(&artifact_url).map_err(|err| {
+                    Error::String(format!("Invalid url {}: {}", artifact_url, err))
+                })?;
+                Ok(hawk::signed_url(credentials, &artifact_url, SIGNED_URL_TTL_SECS)?.into())
+            }
+            _ => Ok(artifact_url),
+        }
     }
 }
 
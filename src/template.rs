@@ -0,0 +1,493 @@
+use crate::taskcluster::{Artifact, TaskGroupTask, TestSetting};
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Template used when fetching the latest run of each task.
+pub const DEFAULT_TEMPLATE: &str = "{label}-{task_id}-{artifact_file}";
+
+/// Template used when fetching selected runs of each task.
+pub const DEFAULT_RUNS_TEMPLATE: &str = "{label}-{task_id}-run{run_id}-{artifact_file}";
+
+/// Value used for placeholders that have no value for a task, e.g. test
+/// setting fields of a build task.
+const MISSING: &str = "none";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Repo,
+    Commit,
+    TaskGroupId,
+    TaskId,
+    Label,
+    RunId,
+    Artifact,
+    ArtifactPath,
+    ArtifactBasename,
+    ArtifactFile,
+    Platform,
+    Os,
+    OsVersion,
+    Arch,
+    Device,
+    BuildType,
+    BuildFlags,
+    Runtime,
+    TestSettingHash,
+}
+
+impl Placeholder {
+    const ALL: &'static [Placeholder] = &[
+        Placeholder::Repo,
+        Placeholder::Commit,
+        Placeholder::TaskGroupId,
+        Placeholder::TaskId,
+        Placeholder::Label,
+        Placeholder::RunId,
+        Placeholder::Artifact,
+        Placeholder::ArtifactPath,
+        Placeholder::ArtifactBasename,
+        Placeholder::ArtifactFile,
+        Placeholder::Platform,
+        Placeholder::Os,
+        Placeholder::OsVersion,
+        Placeholder::Arch,
+        Placeholder::Device,
+        Placeholder::BuildType,
+        Placeholder::BuildFlags,
+        Placeholder::Runtime,
+        Placeholder::TestSettingHash,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Placeholder::Repo => "repo",
+            Placeholder::Commit => "commit",
+            Placeholder::TaskGroupId => "task_group_id",
+            Placeholder::TaskId => "task_id",
+            Placeholder::Label => "label",
+            Placeholder::RunId => "run_id",
+            Placeholder::Artifact => "artifact",
+            Placeholder::ArtifactPath => "artifact_path",
+            Placeholder::ArtifactBasename => "artifact_basename",
+            Placeholder::ArtifactFile => "artifact_file",
+            Placeholder::Platform => "platform",
+            Placeholder::Os => "os",
+            Placeholder::OsVersion => "os_version",
+            Placeholder::Arch => "arch",
+            Placeholder::Device => "device",
+            Placeholder::BuildType => "build_type",
+            Placeholder::BuildFlags => "build_flags",
+            Placeholder::Runtime => "runtime",
+            Placeholder::TestSettingHash => "test_setting_hash",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Placeholder> {
+        if name == "run" {
+            return Some(Placeholder::RunId);
+        }
+        Placeholder::ALL
+            .iter()
+            .find(|placeholder| placeholder.as_str() == name)
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Values of the placeholders for one artifact.
+pub(crate) struct TemplateValues<'a> {
+    pub repo: &'a str,
+    pub commit: Option<&'a str>,
+    pub task: &'a TaskGroupTask,
+    pub test_setting: Option<&'a TestSetting>,
+    pub run_id: Option<u64>,
+    pub artifact: &'a Artifact,
+    /// File name derived from the artifact name, which is unique among the
+    /// artifacts fetched from the run.
+    pub artifact_file: &'a str,
+}
+
+/// Template for the path of each fetched artifact relative to the output
+/// directory, e.g. `{repo}/{platform}/{label}/{run_id}/{artifact_basename}`.
+///
+/// Placeholders in braces are replaced with values from the task, with any
+/// characters that aren't valid in a file name replaced by `-`. `/` in the
+/// template separates directories, and `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl OutputTemplate {
+    pub fn new(template: &str) -> Result<OutputTemplate> {
+        let invalid = |reason: &str| {
+            Err(Error::String(format!(
+                "Invalid output template {}: {}",
+                template, reason
+            )))
+        };
+        if template
+            .split('/')
+            .any(|component| matches!(component, "" | "." | ".."))
+        {
+            return invalid("paths must be relative, without empty, . or .. components");
+        }
+        if template.contains('\\') {
+            return invalid("use / to separate directories");
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return invalid("unclosed {"),
+                        }
+                    }
+                    let Some(placeholder) = Placeholder::from_name(&name) else {
+                        let names = Placeholder::ALL
+                            .iter()
+                            .map(|placeholder| placeholder.as_str())
+                            .collect::<Vec<_>>();
+                        return invalid(&format!(
+                            "unknown placeholder {{{}}}; expected one of {}",
+                            name,
+                            names.join(", ")
+                        ));
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                }
+                '}' => return invalid("unmatched }"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(OutputTemplate {
+            template: template.into(),
+            parts,
+        })
+    }
+
+    /// The default template, which puts every artifact directly in the
+    /// output directory.
+    pub fn default_for(runs: bool) -> OutputTemplate {
+        let template = if runs {
+            DEFAULT_RUNS_TEMPLATE
+        } else {
+            DEFAULT_TEMPLATE
+        };
+        OutputTemplate::new(template).expect("Default template is valid")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    /// Whether the template contains the `{commit}` placeholder, which only
+    /// has a value when fetching the tasks for a commit.
+    pub fn uses_commit(&self) -> bool {
+        self.parts.contains(&Part::Placeholder(Placeholder::Commit))
+    }
+
+    fn value(placeholder: Placeholder, values: &TemplateValues) -> String {
+        let task = values.task;
+        let setting = values.test_setting;
+        let platform = setting.map(|setting| &setting.platform);
+        let value = match placeholder {
+            Placeholder::Repo => Some(values.repo.to_owned()),
+            Placeholder::Commit => values.commit.map(|commit| commit.to_owned()),
+            Placeholder::TaskGroupId => Some(task.status.taskGroupId.clone()),
+            Placeholder::TaskId => Some(task.status.taskId.clone()),
+            Placeholder::Label => Some(task.task.metadata.name.clone()),
+            Placeholder::RunId => values.run_id.map(|run_id| run_id.to_string()),
+            Placeholder::Artifact => Some(values.artifact.name.clone()),
+            Placeholder::ArtifactPath => {
+                return values
+                    .artifact
+                    .name
+                    .split('/')
+                    .map(sanitize)
+                    .collect::<Vec<_>>()
+                    .join("/")
+            }
+            Placeholder::ArtifactBasename => {
+                values.artifact.name.rsplit('/').next().map(Into::into)
+            }
+            Placeholder::ArtifactFile => Some(values.artifact_file.to_owned()),
+            Placeholder::Platform => setting.map(|setting| {
+                setting
+                    .display_name()
+                    .split('/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned()
+            }),
+            Placeholder::Os => platform.map(|platform| platform.os.name.clone()),
+            Placeholder::OsVersion => platform.and_then(|platform| platform.os.version.clone()),
+            Placeholder::Arch => platform.map(|platform| platform.arch.clone()),
+            Placeholder::Device => platform.and_then(|platform| platform.device.clone()),
+            Placeholder::BuildType => setting.map(|setting| setting.build.build_type.clone()),
+            Placeholder::BuildFlags => setting.map(|setting| setting.build_flags().join("-")),
+            Placeholder::Runtime => setting.map(|setting| setting.runtime_flags().join("-")),
+            Placeholder::TestSettingHash => setting.and_then(|setting| setting.hash.clone()),
+        };
+        sanitize(value.as_deref().unwrap_or_default())
+    }
+
+    /// Path of an artifact relative to the output directory.
+    pub(crate) fn render(&self, values: &TemplateValues) -> PathBuf {
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => path.push_str(literal),
+                Part::Placeholder(placeholder) => {
+                    path.push_str(&OutputTemplate::value(*placeholder, values))
+                }
+            }
+        }
+        path.split('/')
+            .map(|component| match component {
+                // Only possible by joining a value to a literal `.`
+                "." | ".." => component.replace('.', "_"),
+                _ => component.to_owned(),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        OutputTemplate::new(s)
+    }
+}
+
+/// The output paths that have been used, to detect templates that give
+/// several artifacts the same path.
+#[derive(Debug, Default)]
+pub(crate) struct PathClaims(Mutex<BTreeMap<PathBuf, (String, String)>>);
+
+impl PathClaims {
+    /// Record that `path` is the output path of `source`, a description of
+    /// an artifact of task `task_id`, failing if it's already the path of
+    /// another artifact.
+    pub(crate) fn claim(&self, path: &Path, task_id: &str, source: &str) -> Result<()> {
+        let mut paths = self.0.lock().unwrap();
+        match paths.get(path) {
+            Some((_, other)) if other != source => Err(Error::String(format!(
+                "Output path {} of {} is already used by {}; the output template needs more placeholders to tell them apart",
+                path.display(),
+                source,
+                other
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                paths.insert(path.to_path_buf(), (task_id.into(), source.into()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Release the paths of the artifacts of a task, which is being fetched
+    /// again after it was rerun.
+    pub(crate) fn release_task(&self, task_id: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, (claimed_by, _)| claimed_by != task_id);
+    }
+}
+
+/// Make a placeholder value safe to use as (part of) a file name.
+fn sanitize(value: &str) -> String {
+    let value = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '-',
+            c if c.is_control() => '-',
+            c => c,
+        })
+        .collect::<String>();
+    match value.trim() {
+        "" => MISSING.into(),
+        "." | ".." => value.replace('.', "_"),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{artifact, task};
+
+    fn render(template: &str, label: &str, artifact_name: &str) -> PathBuf {
+        let task = task(label);
+        let artifact = artifact(artifact_name);
+        OutputTemplate::new(template)
+            .unwrap()
+            .render(&TemplateValues {
+                repo: "mozilla-central",
+                commit: Some("abc123"),
+                task: &task,
+                test_setting: None,
+                run_id: Some(2),
+                artifact: &artifact,
+                artifact_file: artifact_name.rsplit('/').next().unwrap(),
+            })
+    }
+
+    fn error(template: &str) -> String {
+        OutputTemplate::new(template).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            render(
+                "{repo}/{commit}/{label}/{run}/{artifact_basename}",
+                "test-linux",
+                "public/test_info/report.json"
+            ),
+            PathBuf::from("mozilla-central/abc123/test-linux/2/report.json")
+        );
+        assert_eq!(
+            render(
+                "{task_group_id}/{task_id}-run{run_id}/{artifact_path}",
+                "test-linux",
+                "public/logs/log.txt"
+            ),
+            PathBuf::from("G1/T1-run2/public/logs/log.txt")
+        );
+        assert_eq!(
+            render(
+                "{platform}-{build_type}/{artifact}",
+                "build",
+                "public/a.txt"
+            ),
+            PathBuf::from("none-none/public-a.txt")
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            render("{{{task_id}}}-}}{{", "test", "a.txt"),
+            PathBuf::from("{T1}-}{")
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(error("{task_id").contains("unclosed {"));
+        assert!(error("{task_id}}x").contains("unmatched }"));
+        assert!(error("a}").contains("unmatched }"));
+        assert!(error("{nope}").contains("unknown placeholder {nope}"));
+        for template in [
+            "/abs/{task_id}",
+            "a/../{task_id}",
+            "../{task_id}",
+            "a//b",
+            "a/",
+            "./a",
+        ] {
+            assert!(
+                error(template).contains("paths must be relative"),
+                "{}",
+                template
+            );
+        }
+        assert!(error("a\\b").contains("use / to separate directories"));
+    }
+
+    #[test]
+    fn sanitize_values() {
+        assert_eq!(sanitize("test-linux/opt"), "test-linux-opt");
+        assert_eq!(sanitize("a:b?c*d"), "a-b-c-d");
+        assert_eq!(sanitize("a\nb\u{7f}c"), "a-b-c");
+        assert_eq!(sanitize(".."), "__");
+        assert_eq!(sanitize(""), MISSING);
+        assert_eq!(
+            render(
+                "{label}/{artifact_basename}",
+                "test-linux/opt:1",
+                "public/a.json"
+            ),
+            PathBuf::from("test-linux-opt-1/a.json")
+        );
+    }
+
+    #[test]
+    fn dot_components() {
+        assert_eq!(
+            render("{label}/{artifact_path}", "..", "public/../a.json"),
+            PathBuf::from("__/public/__/a.json")
+        );
+        assert_eq!(render(".{label}/a", ".", "a.json"), PathBuf::from("._/a"));
+        assert_eq!(
+            render("x/.{artifact_basename}", "t", "."),
+            PathBuf::from("x/._")
+        );
+    }
+
+    #[test]
+    fn collisions() {
+        let template = "{label}/{artifact_basename}";
+        let first = render(template, "test", "public/a/report.json");
+        let second = render(template, "test", "public/b/report.json");
+        assert_eq!(first, second);
+
+        let claims = PathClaims::default();
+        claims.claim(&first, "T1", "public/a/report.json").unwrap();
+        claims.claim(&first, "T1", "public/a/report.json").unwrap();
+        let err = claims
+            .claim(&second, "T1", "public/b/report.json")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("already used by public/a/report.json"),
+            "{}",
+            err
+        );
+        let other = render(template, "test", "public/b/other.json");
+        claims.claim(&other, "T1", "public/b/other.json").unwrap();
+
+        // A rerun task may reuse the paths of its earlier run
+        claims.release_task("T2");
+        assert!(claims
+            .claim(&first, "T2", "public/a/report.json from run 1")
+            .is_err());
+        claims.release_task("T1");
+        claims
+            .claim(&first, "T2", "public/a/report.json from run 1")
+            .unwrap();
+    }
+}
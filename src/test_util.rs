@@ -0,0 +1,37 @@
+use crate::taskcluster::{Artifact, TaskGroupTask};
+
+/// A completed task `T1` in task group `G1` called `name`, with no runs.
+pub(crate) fn task(name: &str) -> TaskGroupTask {
+    serde_json::from_value(serde_json::json!({
+        "status": {
+            "taskId": "T1",
+            "provisionerId": "p",
+            "workerType": "w",
+            "schedulerId": "s",
+            "taskGroupId": "G1",
+            "deadline": "",
+            "expires": "",
+            "retriesLeft": 0,
+            "state": "completed",
+            "runs": [],
+        },
+        "task": {
+            "provisionerId": "p",
+            "workerType": "w",
+            "schedulerId": "s",
+            "taskGroupId": "G1",
+            "metadata": {"owner": "", "source": "", "description": "", "name": name},
+        },
+    }))
+    .unwrap()
+}
+
+/// An artifact called `name` stored in S3.
+pub(crate) fn artifact(name: &str) -> Artifact {
+    Artifact {
+        storageType: "s3".into(),
+        name: name.into(),
+        expires: "".into(),
+        contentType: "application/json".into(),
+    }
+}
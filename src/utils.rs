@@ -1,63 +1,1115 @@
-use crate::Result;
+use crate::cache::ResponseCache;
+use crate::hawk;
+use crate::progress::DownloadProgress;
+use crate::taskcluster::Credentials;
+use crate::{Error, Result};
+use flate2::read::MultiGzDecoder;
+use log::{debug, info, warn};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{
+    HeaderMap, HeaderName, AUTHORIZATION, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER,
+};
+use reqwest::{StatusCode, Url};
 use serde::de::DeserializeOwned;
-use std::fs::{rename, File};
-use std::io::{copy, BufWriter, Read};
-use std::path::Path;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{remove_file, rename, File};
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-pub fn get_json<T>(
-    client: &reqwest::blocking::Client,
-    url: &str,
-    query: Option<Vec<(String, String)>>,
-    headers: Option<Vec<(String, String)>>,
-) -> Result<T>
+/// Policy for retrying HTTP requests that fail with a transient error.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled for each subsequent retry.
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between attempts, including delays from
+    /// `Retry-After` headers.
+    pub max_backoff: Duration,
+    /// Randomise each delay to between half and all of the computed backoff.
+    pub jitter: bool,
+    /// HTTP status codes that are considered transient.
+    pub retry_statuses: Vec<u16>,
+    /// Use the delay from a `Retry-After` response header when present.
+    pub honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            jitter: true,
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+            honor_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn is_retryable(&self, err: &Error) -> bool {
+        match err {
+            Error::Reqwest(err) => {
+                err.is_connect()
+                    || err.is_timeout()
+                    || err.is_request()
+                    || err.is_body()
+                    || err.is_decode()
+            }
+            Error::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            Error::Status { status, .. } => self.retry_statuses.contains(&status.as_u16()),
+            _ => false,
+        }
+    }
+
+    /// Delay before making attempt number `attempt + 1`, or None if the error
+    /// shouldn't be retried.
+    fn retry_delay(&self, attempt: u32, err: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.is_retryable(err) {
+            return None;
+        }
+        if self.honor_retry_after {
+            if let Error::Status {
+                retry_after: Some(retry_after),
+                ..
+            } = err
+            {
+                return Some((*retry_after).min(self.max_backoff));
+            }
+        }
+        let factor = 2u32.saturating_pow(attempt - 1);
+        let mut delay = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        if self.jitter {
+            delay = delay / 2 + delay.mul_f64(fastrand::f64() / 2.);
+        }
+        Some(delay)
+    }
+}
+
+/// HTTP client used for all requests, applying the configured retry policy
+/// and authentication.
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: reqwest::blocking::Client,
+    retry: RetryPolicy,
+    taskcluster_credentials: Option<Credentials>,
+    github_token: Option<String>,
+    wait_for_github_rate_limit: bool,
+    response_cache: Option<ResponseCache>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(RetryPolicy::default())
+    }
+}
+
+impl Client {
+    pub fn new(retry: RetryPolicy) -> Client {
+        Client {
+            client: reqwest::blocking::Client::new(),
+            retry,
+            taskcluster_credentials: None,
+            github_token: None,
+            wait_for_github_rate_limit: false,
+            response_cache: None,
+        }
+    }
+
+    /// Use the given credentials to authenticate requests to Taskcluster.
+    pub fn with_taskcluster_credentials(mut self, credentials: Credentials) -> Client {
+        self.taskcluster_credentials = Some(credentials);
+        self
+    }
+
+    /// Use the given token to authenticate requests to the GitHub API.
+    pub fn with_github_token(mut self, token: String) -> Client {
+        self.github_token = Some(token);
+        self
+    }
+
+    /// When the GitHub API rate limit is exceeded, wait for it to reset
+    /// rather than failing with `Error::GithubRateLimit`.
+    pub fn with_wait_for_github_rate_limit(mut self, wait: bool) -> Client {
+        self.wait_for_github_rate_limit = wait;
+        self
+    }
+
+    /// Store JSON API responses in `cache`, and reuse them when they are
+    /// unchanged.
+    pub fn with_response_cache(mut self, cache: ResponseCache) -> Client {
+        self.response_cache = Some(cache);
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn taskcluster_credentials(&self) -> Option<&Credentials> {
+        self.taskcluster_credentials.as_ref()
+    }
+
+    pub fn github_token(&self) -> Option<&str> {
+        self.github_token.as_deref()
+    }
+
+    pub fn wait_for_github_rate_limit(&self) -> bool {
+        self.wait_for_github_rate_limit
+    }
+
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.response_cache.as_ref()
+    }
+
+    pub(crate) fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// Send a single request, converting error statuses into `Error::Status`.
+    pub(crate) fn send(&self, req: RequestBuilder) -> Result<Response> {
+        check_status(req.send()?)
+    }
+
+    /// Run `f` until it succeeds, returns a non-retryable error, or the
+    /// retry policy is exhausted.
+    pub(crate) fn with_retry<T, F>(&self, url: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(value) => return Ok(value),
+                Err(err) => match self.retry.retry_delay(attempt, &err) {
+                    Some(delay) => {
+                        warn!(
+                            "Request to {} failed ({}), retrying in {:.1}s",
+                            url,
+                            err,
+                            delay.as_secs_f64()
+                        );
+                        sleep(delay);
+                        attempt += 1;
+                    }
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+}
+
+/// Convert a response with an error status into `Error::Status`.
+pub(crate) fn check_status(resp: Response) -> Result<Response> {
+    match status_error(resp.url().as_str(), resp.status(), resp.headers()) {
+        Some(err) => Err(err),
+        None => Ok(resp),
+    }
+}
+
+/// `Error::Status` for a response with an error status.
+fn status_error(url: &str, status: StatusCode, headers: &HeaderMap) -> Option<Error> {
+    if status.is_client_error() || status.is_server_error() {
+        return Some(Error::Status {
+            url: url.into(),
+            status,
+            retry_after: retry_after(headers),
+        });
+    }
+    None
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    httpdate::parse_http_date(value).ok().map(|date| {
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    })
+}
+
+fn header_value(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+/// Whether a response in the response cache may be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CachePolicy {
+    /// Reuse the cached response if the server confirms it's unchanged.
+    Revalidate,
+    /// The response can't change, so reuse any cached response without a
+    /// request.
+    Immutable,
+    /// The response contains short-lived data, such as credentials, so
+    /// isn't cached.
+    Uncached,
+}
+
+/// The result of looking up a URL in the response cache.
+enum CacheLookup<T> {
+    /// A response that can be used without a request.
+    Fresh(T),
+    /// A response that can be used if the server confirms it's unchanged.
+    Stale {
+        etag: Option<String>,
+        data: T,
+    },
+    Missing,
+}
+
+fn cache_lookup<T>(client: &Client, url: &str, policy: CachePolicy) -> CacheLookup<T>
 where
     T: DeserializeOwned,
 {
-    // TODO - If there's a list then support continuationToken
-    let mut req = client.get(url);
-    if let Some(query_params) = query {
-        req = req.query(&query_params);
+    if policy == CachePolicy::Uncached {
+        return CacheLookup::Missing;
+    }
+    let cached = client
+        .response_cache()
+        .and_then(|cache| cache.get(url))
+        .and_then(|cached| {
+            serde_json::from_str::<T>(&cached.body)
+                .ok()
+                .map(|data| (cached, data))
+        });
+    match cached {
+        Some((cached, data)) if cached.immutable || policy == CachePolicy::Immutable => {
+            debug!("Using cached response for {}", url);
+            CacheLookup::Fresh(data)
+        }
+        Some((cached, data)) => CacheLookup::Stale {
+            etag: cached.etag,
+            data,
+        },
+        None => CacheLookup::Missing,
+    }
+}
+
+/// Error for a 304 response when there's no cached response to use.
+fn not_modified_error(url: &str) -> Error {
+    Error::String(format!(
+        "Got 304 Not Modified from {} without a cached response",
+        url
+    ))
+}
+
+fn cache_store(client: &Client, url: &str, policy: CachePolicy, etag: Option<&str>, body: &str) {
+    if policy == CachePolicy::Uncached {
+        return;
     }
-    if let Some(headers) = headers {
-        for (name, value) in headers.into_iter() {
-            req = req.header(&name, value)
+    if let Some(cache) = client.response_cache() {
+        if let Err(err) = cache.put(url, etag, policy == CachePolicy::Immutable, body) {
+            warn!("Failed to cache response for {}: {}", url, err);
         }
     }
-    let mut resp = req.send()?;
-    resp.error_for_status_ref()?;
-    let mut resp_body = match resp.content_length() {
-        Some(len) => String::with_capacity(len as usize),
-        None => String::new(),
+}
+
+/// Get JSON from `url` using the client's response cache. `send` makes the
+/// request, given the ETag of any cached response to send as
+/// `If-None-Match`.
+pub(crate) fn get_cached_json<T, F>(
+    client: &Client,
+    url: &str,
+    policy: CachePolicy,
+    send: F,
+) -> Result<T>
+where
+    T: DeserializeOwned,
+    F: Fn(Option<&str>) -> Result<Response>,
+{
+    let (etag, data) = match cache_lookup(client, url, policy) {
+        CacheLookup::Fresh(data) => return Ok(data),
+        CacheLookup::Stale { etag, data } => (etag, Some(data)),
+        CacheLookup::Missing => (None, None),
     };
-    resp.read_to_string(&mut resp_body)?;
+    let mut resp = send(etag.as_deref())?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        match data {
+            Some(data) => {
+                debug!("Cached response for {} is unchanged", url);
+                return Ok(data);
+            }
+            None => {
+                debug!("No cached response for {}, refetching", url);
+                resp = send(None)?;
+            }
+        }
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Err(not_modified_error(url));
+        }
+    }
+    let etag = header_value(resp.headers(), ETAG);
+    let resp_body = resp.text()?;
     let data: T = serde_json::from_str(&resp_body)?;
+    cache_store(client, url, policy, etag.as_deref(), &resp_body);
     Ok(data)
 }
 
+pub fn get_json<T>(
+    client: &Client,
+    url: &str,
+    query: Option<Vec<(String, String)>>,
+    headers: Option<Vec<(String, String)>>,
+) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    get_json_with_policy(
+        client,
+        url,
+        query,
+        || Ok(headers.clone()),
+        CachePolicy::Revalidate,
+    )
+}
+
+/// Get JSON from `url`. `headers` is called for each attempt, so that
+/// signed headers are fresh when a request is retried.
+pub(crate) fn get_json_with_policy<T, H>(
+    client: &Client,
+    url: &str,
+    query: Option<Vec<(String, String)>>,
+    headers: H,
+    policy: CachePolicy,
+) -> Result<T>
+where
+    T: DeserializeOwned,
+    H: Fn() -> Result<Option<Vec<(String, String)>>>,
+{
+    let url = match query {
+        Some(query_params) => Url::parse_with_params(url, query_params)
+            .map_err(|err| Error::String(format!("Invalid url {}: {}", url, err)))?
+            .to_string(),
+        None => url.to_owned(),
+    };
+    client.with_retry(&url, || {
+        get_cached_json(client, &url, policy, |etag| {
+            let mut req = client.get(&url);
+            for (name, value) in headers()?.iter().flatten() {
+                req = req.header(name, value)
+            }
+            if let Some(etag) = etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+            client.send(req)
+        })
+    })
+}
+
+/// Current time in seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
 pub fn url(base: &str, path: &str) -> String {
     format!("{}{}", base, path)
 }
 
-pub fn download(
-    client: &reqwest::blocking::Client,
-    name: &Path,
+/// `url` without any `bewit` signature, which differs each time a URL is
+/// signed and shouldn't be logged.
+pub fn unsigned_url(url: &str) -> &str {
+    match url.find("?bewit=").or_else(|| url.find("&bewit=")) {
+        Some(idx) => &url[..idx],
+        None => url,
+    }
+}
+
+/// Path with `suffix` appended to the full file name.
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// SHA-256 of a file's content, as a hex string.
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Details of a partially downloaded file, stored alongside the `.tmp` file
+/// so the download can be resumed later.
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialDownload {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    content_encoding: Option<String>,
+}
+
+impl PartialDownload {
+    fn from_headers(url: &str, headers: &HeaderMap) -> Self {
+        PartialDownload {
+            url: unsigned_url(url).into(),
+            etag: header_value(headers, ETAG),
+            last_modified: header_value(headers, LAST_MODIFIED),
+            content_encoding: header_value(headers, CONTENT_ENCODING),
+        }
+    }
+
+    fn load(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    /// Value for an If-Range header, which must be a strong ETag or a date.
+    fn validator(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+/// Start offset from a `Content-Range: bytes <start>-<end>/<len>` header.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+/// A download that has been completely written to its `.tmp` file.
+struct CompletedDownload {
+    partial: PartialDownload,
+    /// SHA-256 of the bytes received, before removing any content encoding.
+    sha256: String,
+}
+
+/// Reader that computes the SHA-256 of everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn sha256(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Add the contents of the file at `path` to `hasher`.
+async fn hash_file(path: &Path, hasher: &mut Sha256) -> Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..len]);
+    }
+}
+
+/// Check a download against the SHA-256 published for it, which may be of
+/// either the bytes received or the content once decoded.
+fn check_sha256(
     url: &str,
-    compress: bool,
-) -> bool {
-    let tmp_name = name.with_extension("tmp");
-    let mut dest = BufWriter::new(File::create(&tmp_name).unwrap());
-    if let Ok(mut resp) = client.get(url).send() {
-        if compress {
-            zstd::stream::copy_encode(&mut resp, &mut dest, 0).unwrap();
-        } else {
-            copy(&mut resp, &mut dest).unwrap();
-        }
-        rename(&tmp_name, name).unwrap();
-        true
-    } else {
-        if tmp_name.exists() {
-            std::fs::remove_file(tmp_name).unwrap();
-        }
-        false
+    expected: Option<&str>,
+    raw_sha256: &str,
+    content_sha256: &str,
+) -> Result<()> {
+    match expected {
+        Some(expected)
+            if !expected.eq_ignore_ascii_case(raw_sha256)
+                && !expected.eq_ignore_ascii_case(content_sha256) =>
+        {
+            Err(Error::String(format!(
+                "SHA-256 of {} is {}, but {} was expected",
+                url, content_sha256, expected
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove_partial(tmp_name: &Path, meta_name: &Path) -> Result<()> {
+    for path in [tmp_name, meta_name] {
+        if path.exists() {
+            remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Move a completed download into place, removing any content encoding
+/// applied by the server, and return the SHA-256 of its content. The
+/// download is rejected, and the `.tmp` file removed, if it doesn't match
+/// `expected_sha256`.
+fn finish_download(
+    tmp_name: &Path,
+    name: &Path,
+    download: &CompletedDownload,
+    expected_sha256: Option<&str>,
+) -> Result<String> {
+    let url = &download.partial.url;
+    let content_encoding = download.partial.content_encoding.as_deref();
+    if matches!(content_encoding, None | Some("identity")) {
+        if let Err(err) = check_sha256(url, expected_sha256, &download.sha256, &download.sha256) {
+            remove_file(tmp_name)?;
+            return Err(err);
+        }
+        rename(tmp_name, name)?;
+        return Ok(download.sha256.clone());
+    }
+    let src = BufReader::new(File::open(tmp_name)?);
+    let src: Box<dyn Read> = match content_encoding {
+        Some("gzip") | Some("x-gzip") => Box::new(MultiGzDecoder::new(src)),
+        _ => {
+            return Err(Error::String(format!(
+                "Unsupported Content-Encoding {}",
+                content_encoding.unwrap_or_default()
+            )))
+        }
+    };
+    let mut src = HashingReader::new(src);
+    let out_name = with_suffix(name, ".out.tmp");
+    let mut dest = BufWriter::new(File::create(&out_name)?);
+    let result = io::copy(&mut src, &mut dest).and_then(|_| dest.flush());
+    drop(dest);
+    if let Err(err) = result {
+        remove_file(&out_name)?;
+        return Err(err.into());
+    }
+    let content_sha256 = src.sha256();
+    if let Err(err) = check_sha256(url, expected_sha256, &download.sha256, &content_sha256) {
+        remove_file(&out_name)?;
+        remove_file(tmp_name)?;
+        return Err(err);
+    }
+    rename(&out_name, name)?;
+    remove_file(tmp_name)?;
+    Ok(content_sha256)
+}
+
+/// Limits on the number of concurrent requests made by an `AsyncClient`.
+#[derive(Debug, Clone)]
+pub struct Concurrency {
+    /// Maximum number of concurrent API requests.
+    pub api: usize,
+    /// Maximum number of concurrent artifact downloads.
+    pub downloads: usize,
+    /// Maximum number of concurrent requests to any one host.
+    pub per_host: usize,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Concurrency {
+            api: 8,
+            downloads: 8,
+            per_host: 8,
+        }
+    }
+}
+
+/// Run blocking work, such as file I/O, on a thread where it won't hold up
+/// other requests.
+pub(crate) async fn spawn_blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Error::String(format!("Background task failed: {}", err)))?
+}
+
+/// Asynchronous counterpart to `Client`, which makes requests concurrently
+/// within the given limits. It must be used within a Tokio runtime.
+pub(crate) struct AsyncClient<'a> {
+    client: &'a Client,
+    http: reqwest::Client,
+    raw_http: reqwest::Client,
+    api_limit: Semaphore,
+    download_limit: Semaphore,
+    per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl<'a> AsyncClient<'a> {
+    pub(crate) fn new(client: &'a Client, concurrency: &Concurrency) -> Result<AsyncClient<'a>> {
+        let per_host = concurrency.per_host.max(1);
+        Ok(AsyncClient {
+            client,
+            http: reqwest::Client::builder()
+                .pool_max_idle_per_host(per_host)
+                .build()?,
+            raw_http: reqwest::Client::builder()
+                .pool_max_idle_per_host(per_host)
+                .no_gzip()
+                .build()?,
+            api_limit: Semaphore::new(concurrency.api.max(1)),
+            download_limit: Semaphore::new(concurrency.downloads.max(1)),
+            per_host,
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(crate) fn client(&self) -> &Client {
+        self.client
+    }
+
+    async fn host_permit(&self, url: &str) -> Option<OwnedSemaphorePermit> {
+        let host = Url::parse(url).ok()?.host_str()?.to_owned();
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone();
+        semaphore.acquire_owned().await.ok()
+    }
+
+    /// Run `f` until it succeeds, returns a non-retryable error, or the
+    /// retry policy is exhausted.
+    async fn with_retry<T, F, Fut>(&self, url: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(err) => match self.client.retry.retry_delay(attempt, &err) {
+                    Some(delay) => {
+                        warn!(
+                            "Request to {} failed ({}), retrying in {:.1}s",
+                            url,
+                            err,
+                            delay.as_secs_f64()
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => return Err(err),
+                },
+            }
+        }
+    }
+
+    /// Get JSON from `url`, using the client's response cache. `headers` is
+    /// called for each attempt, so that signed headers are fresh when a
+    /// request is retried.
+    pub(crate) async fn get_json<T, H>(
+        &self,
+        url: &str,
+        headers: H,
+        policy: CachePolicy,
+    ) -> Result<T>
+    where
+        T: DeserializeOwned,
+        H: Fn() -> Result<Option<Vec<(String, String)>>>,
+    {
+        let headers = &headers;
+        self.with_retry(url, move || async move {
+            let (etag, data) = match cache_lookup(self.client, url, policy) {
+                CacheLookup::Fresh(data) => return Ok(data),
+                CacheLookup::Stale { etag, data } => (etag, Some(data)),
+                CacheLookup::Missing => (None, None),
+            };
+            let _permit = self.api_limit.acquire().await;
+            let _host_permit = self.host_permit(url).await;
+            let mut resp = self.send_get(url, headers()?, etag.as_deref()).await?;
+            if resp.status() == StatusCode::NOT_MODIFIED {
+                match data {
+                    Some(data) => {
+                        debug!("Cached response for {} is unchanged", url);
+                        return Ok(data);
+                    }
+                    None => {
+                        debug!("No cached response for {}, refetching", url);
+                        resp = self.send_get(url, headers()?, None).await?;
+                    }
+                }
+                if resp.status() == StatusCode::NOT_MODIFIED {
+                    return Err(not_modified_error(url));
+                }
+            }
+            let etag = header_value(resp.headers(), ETAG);
+            let resp_body = resp.text().await?;
+            let data: T = serde_json::from_str(&resp_body)?;
+            cache_store(self.client, url, policy, etag.as_deref(), &resp_body);
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Send a single GET request, with `If-None-Match` set to `etag` if
+    /// given.
+    async fn send_get(
+        &self,
+        url: &str,
+        headers: Option<Vec<(String, String)>>,
+        etag: Option<&str>,
+    ) -> Result<reqwest::Response> {
+        let mut req = self.http.get(url);
+        for (name, value) in headers.iter().flatten() {
+            req = req.header(name, value);
+        }
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        let resp = req.send().await?;
+        if let Some(err) = status_error(resp.url().as_str(), resp.status(), resp.headers()) {
+            return Err(err);
+        }
+        Ok(resp)
+    }
+
+    /// Send `body` as JSON in a PUT request to `url`, signed with
+    /// `credentials`, and parse the JSON response.
+    pub(crate) async fn put_json<T>(
+        &self,
+        url: &Url,
+        credentials: &Credentials,
+        body: &serde_json::Value,
+    ) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let body = serde_json::to_string(body)?;
+        self.with_retry(url.as_str(), || async {
+            let _permit = self.api_limit.acquire().await;
+            let _host_permit = self.host_permit(url.as_str()).await;
+            let resp = self
+                .http
+                .put(url.clone())
+                .header(
+                    AUTHORIZATION,
+                    hawk::authorization_header(credentials, "PUT", url)?,
+                )
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await?;
+            if let Some(err) = status_error(resp.url().as_str(), resp.status(), resp.headers()) {
+                return Err(err);
+            }
+            Ok(serde_json::from_str(&resp.text().await?)?)
+        })
+        .await
+    }
+
+    /// Download `url` into `tmp_name`, resuming from any existing partial file
+    /// when the server supports range requests and the resource hasn't changed.
+    async fn download_once(
+        &self,
+        tmp_name: &Path,
+        meta_name: &Path,
+        url: &str,
+        progress: DownloadProgress<'_>,
+    ) -> Result<CompletedDownload> {
+        let _permit = self.download_limit.acquire().await;
+        let _host_permit = self.host_permit(url).await;
+        loop {
+            let existing_len = tmp_name.metadata().map(|meta| meta.len()).unwrap_or(0);
+            let partial = if existing_len > 0 {
+                PartialDownload::load(meta_name).filter(|partial| {
+                    partial.url == unsigned_url(url) && partial.validator().is_some()
+                })
+            } else {
+                None
+            };
+
+            let mut req = self.raw_http.get(url);
+            if let Some(ref partial) = partial {
+                req = req
+                    .header(RANGE, format!("bytes={}-", existing_len))
+                    .header(IF_RANGE, partial.validator().unwrap());
+            }
+            let mut resp = req.send().await?;
+            let status = resp.status();
+            if let Some(err) = status_error(resp.url().as_str(), status, resp.headers()) {
+                if partial.is_some() && status == StatusCode::RANGE_NOT_SATISFIABLE {
+                    // The partial file doesn't correspond to the current resource.
+                    remove_partial(tmp_name, meta_name)?;
+                    continue;
+                }
+                return Err(err);
+            }
+
+            let (file, partial, downloaded) = match partial {
+                Some(partial) if status == StatusCode::PARTIAL_CONTENT => {
+                    if content_range_start(resp.headers()) != Some(existing_len) {
+                        remove_partial(tmp_name, meta_name)?;
+                        return Err(Error::String(format!(
+                            "Unexpected Content-Range resuming download of {}",
+                            url
+                        )));
+                    }
+                    info!("Resuming download of {} from byte {}", url, existing_len);
+                    let file = tokio::fs::OpenOptions::new()
+                        .append(true)
+                        .open(tmp_name)
+                        .await?;
+                    (file, partial, existing_len)
+                }
+                _ => {
+                    let partial = PartialDownload::from_headers(url, resp.headers());
+                    partial.save(meta_name)?;
+                    (tokio::fs::File::create(tmp_name).await?, partial, 0)
+                }
+            };
+            let total = resp.content_length().map(|len| downloaded + len);
+            progress.started(downloaded, total);
+            let mut hasher = Sha256::new();
+            if downloaded > 0 {
+                hash_file(tmp_name, &mut hasher).await?;
+            }
+            let mut received = downloaded;
+            let mut dest = tokio::io::BufWriter::new(file);
+            let result = async {
+                while let Some(chunk) = resp.chunk().await? {
+                    dest.write_all(&chunk).await?;
+                    hasher.update(&chunk);
+                    received += chunk.len() as u64;
+                    progress.advanced(chunk.len() as u64);
+                }
+                Ok::<_, Error>(())
+            }
+            .await;
+            // Flush even if the download failed, so it can be resumed.
+            dest.flush().await?;
+            drop(dest);
+            result?;
+            match total {
+                // Keep what was received, so a retry can resume from there.
+                Some(total) if received < total => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "Download of {} ended after {} of {} bytes",
+                            unsigned_url(url),
+                            received,
+                            total
+                        ),
+                    )));
+                }
+                Some(total) if received > total => {
+                    remove_partial(tmp_name, meta_name)?;
+                    return Err(Error::String(format!(
+                        "Download of {} was {} bytes, but Content-Length promised {}",
+                        unsigned_url(url),
+                        received,
+                        total
+                    )));
+                }
+                _ => {}
+            }
+            return Ok(CompletedDownload {
+                partial,
+                sha256: format!("{:x}", hasher.finalize()),
+            });
+        }
+    }
+
+    /// Download `url` to `name`, removing any content encoding applied by
+    /// the server, and return the SHA-256 of its content. If
+    /// `expected_sha256` is given, the download fails unless its content
+    /// has that SHA-256.
+    pub(crate) async fn download(
+        &self,
+        name: &Path,
+        url: &str,
+        expected_sha256: Option<&str>,
+        progress: DownloadProgress<'_>,
+    ) -> Result<String> {
+        let tmp_name = with_suffix(name, ".tmp");
+        let meta_name = with_suffix(name, ".tmp.meta");
+        let result = match self
+            .with_retry(unsigned_url(url), || {
+                self.download_once(&tmp_name, &meta_name, url, progress)
+            })
+            .await
+        {
+            Ok(download) => {
+                let (tmp_name, name) = (tmp_name.clone(), name.to_path_buf());
+                let expected_sha256 = expected_sha256.map(|sha256| sha256.to_owned());
+                spawn_blocking(move || {
+                    finish_download(&tmp_name, &name, &download, expected_sha256.as_deref())
+                })
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => {
+                if meta_name.exists() {
+                    remove_file(&meta_name)?;
+                }
+            }
+            Err(_) => {
+                // Keep the partial file if a later run will be able to resume it.
+                let resumable = tmp_name.exists()
+                    && PartialDownload::load(&meta_name)
+                        .is_some_and(|partial| partial.validator().is_some());
+                if !resumable {
+                    remove_partial(&tmp_name, &meta_name)?;
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16, retry_after: Option<Duration>) -> Error {
+        Error::Status {
+            url: "https://example.com".into(),
+            status: StatusCode::from_u16(code).unwrap(),
+            retry_after,
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            jitter: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            ..policy()
+        };
+        let err = status(503, None);
+        let delays = (1..10)
+            .map(|attempt| policy.retry_delay(attempt, &err).unwrap().as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(policy.retry_delay(10, &err), None);
+        assert_eq!(RetryPolicy::none().retry_delay(1, &err), None);
+    }
+
+    #[test]
+    fn retryable_statuses() {
+        let policy = policy();
+        assert!(policy.retry_delay(1, &status(429, None)).is_some());
+        assert!(policy.retry_delay(1, &status(404, None)).is_none());
+        assert!(policy
+            .retry_delay(1, &Error::String("failed".into()))
+            .is_none());
+        let policy = RetryPolicy {
+            retry_statuses: vec![404],
+            ..policy
+        };
+        assert!(policy.retry_delay(1, &status(404, None)).is_some());
+        assert!(policy.retry_delay(1, &status(503, None)).is_none());
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy()
+        };
+        let err = status(503, None);
+        for _ in 0..100 {
+            let delay = policy.retry_delay(3, &err).unwrap();
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn honor_retry_after() {
+        let policy = policy();
+        let delay = |retry_after| policy.retry_delay(1, &status(503, Some(retry_after)));
+        assert_eq!(
+            delay(Duration::from_secs(30)),
+            Some(Duration::from_secs(30))
+        );
+        // Capped at the maximum backoff
+        assert_eq!(
+            delay(Duration::from_secs(3600)),
+            Some(Duration::from_secs(60))
+        );
+        let policy = RetryPolicy {
+            honor_retry_after: false,
+            ..policy
+        };
+        assert_eq!(
+            policy.retry_delay(1, &status(503, Some(Duration::from_secs(30)))),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let parse = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            retry_after(&headers)
+        };
+        assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse(" 5 "), Some(Duration::from_secs(5)));
+        assert_eq!(parse("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(600));
+        let delay = parse(&later).unwrap();
+        assert!(delay > Duration::from_secs(590) && delay <= Duration::from_secs(600));
+        assert_eq!(parse("soon"), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
     }
 }
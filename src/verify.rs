@@ -0,0 +1,329 @@
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::taskcluster::{Artifact, Taskcluster, CHAIN_OF_TRUST_ARTIFACT};
+use crate::utils::{sha256_file, spawn_blocking, AsyncClient};
+use crate::{get_repo_ci, Client, Concurrency, Error, Project, Result};
+use futures_util::future::join_all;
+use log::warn;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Result of checking a file listed in a manifest.
+#[derive(Debug)]
+pub enum VerifyStatus {
+    /// The file matches the manifest, and Taskcluster doesn't publish a
+    /// hash for the artifact or it wasn't checked.
+    Ok,
+    /// The file matches the manifest and the SHA-256 published by Taskcluster.
+    Verified,
+    Missing,
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    Sha256Mismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The artifact content doesn't match the SHA-256 published by Taskcluster.
+    TaskclusterMismatch {
+        expected: String,
+        actual: String,
+    },
+    Failed(Error),
+}
+
+impl VerifyStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Verified => "verified",
+            VerifyStatus::Missing => "missing",
+            VerifyStatus::SizeMismatch { .. } => "size-mismatch",
+            VerifyStatus::Sha256Mismatch { .. } => "sha256-mismatch",
+            VerifyStatus::TaskclusterMismatch { .. } => "taskcluster-mismatch",
+            VerifyStatus::Failed(_) => "failed",
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        matches!(self, VerifyStatus::Ok | VerifyStatus::Verified)
+    }
+
+    /// Description of why the file is invalid, if it is.
+    pub fn message(&self) -> Option<String> {
+        match self {
+            VerifyStatus::Ok | VerifyStatus::Verified => None,
+            VerifyStatus::Missing => Some("File is missing".into()),
+            VerifyStatus::SizeMismatch { expected, actual } => Some(format!(
+                "Size is {} bytes, but the manifest has {}",
+                actual, expected
+            )),
+            VerifyStatus::Sha256Mismatch { expected, actual } => Some(format!(
+                "SHA-256 is {}, but the manifest has {}",
+                actual, expected
+            )),
+            VerifyStatus::TaskclusterMismatch { expected, actual } => Some(format!(
+                "SHA-256 of the content is {}, but Taskcluster has {}",
+                actual, expected
+            )),
+            VerifyStatus::Failed(err) => Some(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyResult {
+    pub entry: ManifestEntry,
+    /// Path of the local file.
+    pub path: PathBuf,
+    pub status: VerifyStatus,
+}
+
+/// Check a file against its manifest entry.
+fn check_file(path: &Path, entry: &ManifestEntry) -> std::result::Result<(), VerifyStatus> {
+    let Ok(metadata) = path.metadata() else {
+        return Err(VerifyStatus::Missing);
+    };
+    if metadata.len() != entry.size {
+        return Err(VerifyStatus::SizeMismatch {
+            expected: entry.size,
+            actual: metadata.len(),
+        });
+    }
+    let sha256 = sha256_file(path).map_err(VerifyStatus::Failed)?;
+    if sha256 != entry.sha256 {
+        return Err(VerifyStatus::Sha256Mismatch {
+            expected: entry.sha256.clone(),
+            actual: sha256,
+        });
+    }
+    Ok(())
+}
+
+/// SHA-256 hashes Taskcluster publishes for `artifacts` of a run, from its
+/// chain of trust and the object service.
+async fn published_hashes(
+    client: &AsyncClient<'_>,
+    taskcluster: &Taskcluster,
+    task_id: &str,
+    run_id: u64,
+    artifacts: &[&str],
+) -> Result<BTreeMap<String, String>> {
+    let listing = taskcluster
+        .get_run_artifacts(client, task_id, run_id)
+        .await?;
+    let mut hashes = if listing
+        .iter()
+        .any(|artifact| artifact.name == CHAIN_OF_TRUST_ARTIFACT)
+    {
+        taskcluster
+            .get_chain_of_trust(client, task_id, run_id)
+            .await?
+    } else {
+        BTreeMap::new()
+    };
+    let objects = listing
+        .iter()
+        .filter(|artifact| artifact.storageType == "object" && artifacts.contains(&&*artifact.name))
+        .collect::<Vec<&Artifact>>();
+    for (artifact, download) in objects.iter().zip(
+        join_all(objects.iter().map(|artifact| {
+            taskcluster.get_object_download(client, task_id, Some(run_id), artifact)
+        }))
+        .await,
+    ) {
+        if let Some(sha256) = download?.sha256 {
+            hashes.insert(artifact.name.clone(), sha256);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Check the files fetched from one run of a task.
+async fn verify_run(
+    client: &AsyncClient<'_>,
+    taskcluster: &Taskcluster,
+    out_dir: &Path,
+    (task_id, run_id): (String, Option<u64>),
+    entries: Vec<ManifestEntry>,
+    check_taskcluster: bool,
+) -> Vec<VerifyResult> {
+    let hashes = match run_id {
+        Some(run_id) if check_taskcluster => {
+            let names = entries
+                .iter()
+                .map(|entry| entry.artifact.as_str())
+                .collect::<Vec<_>>();
+            published_hashes(client, taskcluster, &task_id, run_id, &names)
+                .await
+                .unwrap_or_else(|err| {
+                    warn!(
+                        "Fetching the SHA-256 of the artifacts of task {} failed: {}",
+                        task_id, err
+                    );
+                    BTreeMap::new()
+                })
+        }
+        _ => BTreeMap::new(),
+    };
+    let mut results = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let path = out_dir.join(&entry.path);
+        let (check_path, check_entry) = (path.clone(), entry.clone());
+        let checked = spawn_blocking(move || Ok(check_file(&check_path, &check_entry))).await;
+        let status = match checked {
+            Err(err) => VerifyStatus::Failed(err),
+            Ok(Err(status)) => status,
+            Ok(Ok(())) => match (hashes.get(&entry.artifact), &entry.content_sha256) {
+                (Some(expected), Some(sha256)) if !expected.eq_ignore_ascii_case(sha256) => {
+                    VerifyStatus::TaskclusterMismatch {
+                        expected: expected.clone(),
+                        actual: sha256.clone(),
+                    }
+                }
+                (Some(_), Some(_)) => VerifyStatus::Verified,
+                _ => VerifyStatus::Ok,
+            },
+        };
+        results.push(VerifyResult {
+            entry,
+            path,
+            status,
+        });
+    }
+    results
+}
+
+/// Check each file listed in `manifest` still has the size and SHA-256
+/// recorded when it was fetched. If `check_taskcluster` is set, the content
+/// is also compared with the SHA-256 that Taskcluster publishes for the
+/// artifact, where there is one.
+pub fn verify_manifest(
+    client: &Client,
+    taskcluster_base: Option<&str>,
+    project: &Project,
+    manifest: Manifest,
+    check_taskcluster: bool,
+) -> Result<Vec<VerifyResult>> {
+    let ci = get_repo_ci(project, taskcluster_base);
+    let taskcluster = ci.taskcluster();
+
+    let mut runs: BTreeMap<(String, Option<u64>), Vec<ManifestEntry>> = BTreeMap::new();
+    for entry in manifest.artifacts {
+        runs.entry((entry.task_id.clone(), entry.run_id))
+            .or_default()
+            .push(entry);
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let client = AsyncClient::new(client, &Concurrency::default())?;
+        let results = join_all(runs.into_iter().map(|(run, entries)| {
+            verify_run(
+                &client,
+                taskcluster,
+                &manifest.out_dir,
+                run,
+                entries,
+                check_taskcluster,
+            )
+        }))
+        .await;
+        Ok(results.into_iter().flatten().collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::OutputEncoding;
+    use crate::test_util::{artifact, task};
+    use crate::{DownloadStatus, HgmoProject, TaskDownload, TaskSource};
+    use std::fs;
+
+    #[test]
+    fn verify_moved_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out_dir = tmp.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        let names = ["intact", "modified", "truncated", "removed"];
+        let downloads = names
+            .iter()
+            .map(|name| {
+                let path = out_dir.join(name);
+                fs::write(&path, "content").unwrap();
+                TaskDownload {
+                    task: task("test-linux"),
+                    run_id: Some(0),
+                    artifact: Some(artifact(&format!("public/{}", name))),
+                    status: DownloadStatus::Downloaded(path),
+                    sha256: None,
+                }
+            })
+            .collect::<Vec<_>>();
+        let manifest = Manifest::new(
+            "mozilla-central",
+            &TaskSource::Task("T1".into()),
+            &out_dir,
+            &downloads,
+            &OutputEncoding::Raw,
+        )
+        .unwrap();
+        manifest.write(&out_dir.join("manifest.json")).unwrap();
+
+        // Verification works once the output and manifest are moved
+        let moved = tmp.path().join("moved");
+        fs::rename(&out_dir, &moved).unwrap();
+        fs::write(moved.join("modified"), "CONTENT").unwrap();
+        fs::write(moved.join("truncated"), "cont").unwrap();
+        fs::remove_file(moved.join("removed")).unwrap();
+
+        let manifest = Manifest::load(&moved.join("manifest.json")).unwrap();
+        let results = verify_manifest(
+            &Client::new(crate::RetryPolicy::none()),
+            None,
+            &Project::Hgmo(HgmoProject::new("mozilla-central")),
+            manifest,
+            false,
+        )
+        .unwrap();
+        let statuses = results
+            .iter()
+            .map(|result| {
+                assert_eq!(result.path, moved.join(&result.entry.path));
+                (result.entry.path.to_str().unwrap(), result.status.as_str())
+            })
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            statuses,
+            BTreeMap::from([
+                ("intact", "ok"),
+                ("modified", "sha256-mismatch"),
+                ("truncated", "size-mismatch"),
+                ("removed", "missing"),
+            ])
+        );
+    }
+
+    #[test]
+    fn out_dir_outside_manifest_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out_dir = tmp.path().join("out");
+        let manifest_dir = tmp.path().join("manifests");
+        fs::create_dir(&out_dir).unwrap();
+        fs::create_dir(&manifest_dir).unwrap();
+        let manifest = Manifest::new(
+            "mozilla-central",
+            &TaskSource::Task("T1".into()),
+            &out_dir,
+            &[],
+            &OutputEncoding::Raw,
+        )
+        .unwrap();
+        let path = manifest_dir.join("manifest.json");
+        manifest.write(&path).unwrap();
+        assert_eq!(Manifest::load(&path).unwrap().out_dir, out_dir);
+    }
+}